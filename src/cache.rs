@@ -1,104 +1,497 @@
 use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::mounts::Filesystems;
 use linked_hash_map::LinkedHashMap;
-use std::fs::{create_dir_all, OpenOptions};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use yaml_rust::{yaml, Yaml, YamlEmitter};
 
-fn read_current_state_file(previous_visits: PathBuf, yaml_string: &mut String) {
+/// How often saving a visit looks for saved directories that no longer exist
+const MISSING_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn state_error(previous_visits: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+    move |source| Error::State {
+        file: previous_visits.to_path_buf(),
+        source,
+    }
+}
+
+fn read_current_state_file(previous_visits: &Path) -> Result<String> {
+    let mut yaml_string = String::new();
+    let result = File::open(previous_visits).and_then(|mut f| f.read_to_string(&mut yaml_string));
+    match result {
+        Ok(_) => Ok(yaml_string),
+        // Nothing visited yet
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(yaml_string),
+        Err(e) => Err(state_error(previous_visits)(e)),
+    }
+}
+
+/// Parse state file contents, gives None if they are not a valid state
+fn parse_state(yaml_string: &str) -> Option<yaml::Hash> {
+    let datas = yaml::YamlLoader::load_from_str(yaml_string).ok()?;
+    match datas.into_iter().next() {
+        None | Some(Yaml::Null) => Some(yaml::Hash::new()),
+        Some(Yaml::Hash(hash)) => Some(hash),
+        Some(_) => None,
+    }
+}
+
+/// Path next to the state file, used for the lock, temporary and backup files
+fn state_sibling(previous_visits: &Path, suffix: &str) -> PathBuf {
+    let mut name = previous_visits.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Wait for other fastjump processes to finish updating state, released when the file is dropped
+fn lock_state(previous_visits: &Path) -> std::io::Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(state_sibling(previous_visits, ".lock"))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+/// Replace the state file in one step so readers never see a partially written file
+fn write_state_file(previous_visits: &Path, data_hash: &yaml::Hash) -> std::io::Result<()> {
+    let mut writer = String::new();
+    let mut emitter = YamlEmitter::new(&mut writer);
+    emitter
+        .dump(&Yaml::Hash(data_hash.clone()))
+        .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+
+    let tmp = state_sibling(previous_visits, &format!(".tmp{}", process::id()));
+    let written = File::create(&tmp)
+        .and_then(|mut f| {
+            f.write_all(writer.as_bytes())?;
+            f.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, previous_visits));
+    // A half written file would be left next to the state for good
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Move a state file that can not be parsed out of the way so it can be inspected later
+fn backup_corrupt_state(previous_visits: &Path) -> std::io::Result<()> {
+    let backup = state_sibling(previous_visits, &format!(".corrupt-{}", now()));
+    fs::rename(previous_visits, &backup)?;
+    eprintln!(
+        "Warning: State {} could not be read, moved to {} and starting over",
+        previous_visits.display(),
+        backup.display()
+    );
+    Ok(())
+}
+
+/// Read, modify and write back the state while holding the lock
+fn modify_state<F: FnOnce(&mut yaml::Hash)>(previous_visits: PathBuf, modify: F) -> Result<()> {
     let mut previous_visits_dir = previous_visits.clone();
     previous_visits_dir.pop();
     let _ = create_dir_all(previous_visits_dir.as_path());
 
-    let maybe_file = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .open(previous_visits.clone());
-    match maybe_file {
-        Ok(mut file) => file.read_to_string(yaml_string).unwrap(),
-        Err(e) => {
-            println!(
-                "Error: Failed to read state {}: {}",
-                previous_visits.to_str().unwrap(),
-                e
-            );
-            0
+    let _lock = lock_state(&previous_visits).map_err(state_error(&previous_visits))?;
+    let yaml_string = read_current_state_file(&previous_visits)?;
+    let mut data_hash = match parse_state(&yaml_string) {
+        Some(data_hash) => data_hash,
+        None => {
+            backup_corrupt_state(&previous_visits).map_err(state_error(&previous_visits))?;
+            yaml::Hash::new()
         }
     };
+
+    modify(&mut data_hash);
+    write_state_file(&previous_visits, &data_hash).map_err(state_error(&previous_visits))
 }
 
-fn write_new_state_file(previous_visits: PathBuf, location: String, data_hash: &mut yaml::Hash) {
-    let key = Yaml::String(location);
-    let previous_value = data_hash
-        .entry(key.clone())
-        .or_insert(Yaml::Integer(0))
-        .as_i64()
-        .unwrap();
-    data_hash.insert(key, Yaml::Integer(previous_value + 1));
+/// Visits to a single location
+#[derive(Clone, Debug, PartialEq)]
+pub struct Visit {
+    pub count: i64,
+    /// Seconds since the unix epoch
+    pub last_visit: u64,
+}
 
-    let mut writer = String::new();
-    let mut emitter = YamlEmitter::new(&mut writer);
-    emitter.dump(&Yaml::Hash(data_hash.clone())).unwrap();
-    let f = OpenOptions::new().write(true).open(previous_visits.clone());
-    match f {
-        Ok(mut f) => f.write_all(writer.as_bytes()).unwrap(),
-        Err(e) => {
-            println!(
-                "Error: Failed to write state {}: {}",
-                previous_visits.to_str().unwrap(),
-                e
-            );
+impl Visit {
+    /// Visit count decayed by the time since the last visit, halved every half_life seconds
+    ///
+    /// A half_life of 0 is not accepted by the config, it is treated as no decay rather than divided by.
+    pub fn frecency(&self, now: u64, half_life: u64) -> i64 {
+        if half_life == 0 {
+            return self.count;
         }
+        let age = now.saturating_sub(self.last_visit) as f64;
+        let decay = 0.5f64.powf(age / half_life as f64);
+        (self.count as f64 * decay).round() as i64
     }
 }
 
-fn read_current_state_to_yamlmap(config: Config) -> yaml::Hash {
-    let default = yaml::Hash::new();
-    if config.previous_visits.is_none() {
-        return default;
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn visit_from_yaml(value: &Yaml, now: u64) -> Option<Visit> {
+    match value {
+        // Saved before visit times were, count them as visited when first seen again
+        Yaml::Integer(count) => Some(Visit {
+            count: *count,
+            last_visit: now,
+        }),
+        Yaml::Hash(_) => Some(Visit {
+            count: value["count"].as_i64()?,
+            last_visit: value["last_visit"].as_i64()?.try_into().ok()?,
+        }),
+        _ => None,
     }
-    let previous_visits = config.previous_visits.unwrap();
-    let mut yaml_string = String::new();
-    read_current_state_file(previous_visits, &mut yaml_string);
-
-    let datas = yaml::YamlLoader::load_from_str(&yaml_string).unwrap();
-    let default = Yaml::Hash(yaml::Hash::new());
-    let data = if datas.is_empty() {
-        &default
-    } else {
-        &datas[0]
-    };
-    data.clone().into_hash().unwrap()
 }
 
-pub fn get_current_state(config: Config) -> LinkedHashMap<String, i64> {
-    let mut res: LinkedHashMap<String, i64> = LinkedHashMap::new();
-    let data = read_current_state_to_yamlmap(config);
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Key location is saved under, bytes that are not UTF-8 are shown as `\xNN` and `\` as `\\`
+/// so no two locations share a key
+fn location_key(location: &Path) -> String {
+    let mut key = String::new();
+    for chunk in location.as_os_str().as_bytes().utf8_chunks() {
+        key.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            key.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    key
+}
+
+/// Location saved under key, YAML only holds text so paths that are not UTF-8 or that are
+/// escaped in the key are kept as hex in `raw_path`
+fn location_from_yaml(key: Yaml, value: &Yaml) -> Option<PathBuf> {
+    match value["raw_path"].as_str().and_then(from_hex) {
+        Some(bytes) => Some(PathBuf::from(OsString::from_vec(bytes))),
+        None => key.into_string().map(PathBuf::from),
+    }
+}
+
+fn visit_to_yaml(location: &Path, visit: &Visit) -> Yaml {
+    let mut hash = yaml::Hash::new();
+    hash.insert(
+        Yaml::String(String::from("count")),
+        Yaml::Integer(visit.count),
+    );
+    hash.insert(
+        Yaml::String(String::from("last_visit")),
+        Yaml::Integer(visit.last_visit as i64),
+    );
+    // The key is only read back as the path when it is the path as is
+    if location.to_str() != Some(location_key(location).as_str()) {
+        hash.insert(
+            Yaml::String(String::from("raw_path")),
+            Yaml::String(to_hex(location.as_os_str().as_bytes())),
+        );
+    }
+    Yaml::Hash(hash)
+}
+
+fn state_from_yaml(data: yaml::Hash) -> LinkedHashMap<PathBuf, Visit> {
+    let mut res: LinkedHashMap<PathBuf, Visit> = LinkedHashMap::new();
+    let now = now();
 
     for (key, value) in data {
-        let k = key.into_string().unwrap();
-        let v = value.into_i64().unwrap();
-        res.insert(k, v);
+        let visit = visit_from_yaml(&value, now);
+        if let (Some(k), Some(v)) = (location_from_yaml(key, &value), visit) {
+            res.insert(k, v);
+        }
     }
 
     res
 }
 
-pub fn save(config: Config, location: String) {
+fn state_to_yaml(state: &LinkedHashMap<PathBuf, Visit>) -> yaml::Hash {
+    state
+        .iter()
+        .map(|(path, visit)| (Yaml::String(location_key(path)), visit_to_yaml(path, visit)))
+        .collect()
+}
+
+/// Change saved visits while holding the lock so concurrent updates are not lost
+pub(crate) fn update_state<F>(previous_visits: PathBuf, modify: F) -> Result<()>
+where
+    F: FnOnce(&mut LinkedHashMap<PathBuf, Visit>),
+{
+    modify_state(previous_visits, |data_hash| {
+        let mut state = state_from_yaml(data_hash.clone());
+        modify(&mut state);
+        *data_hash = state_to_yaml(&state);
+    })
+}
+
+fn add_visit(state: &mut LinkedHashMap<PathBuf, Visit>, location: PathBuf) {
+    let previous_count = state.get(&location).map(|v| v.count).unwrap_or(0);
+    let visit = Visit {
+        count: previous_count + 1,
+        last_visit: now(),
+    };
+    state.insert(location, visit);
+}
+
+fn read_current_state_to_yamlmap(config: Config) -> Result<yaml::Hash> {
+    let previous_visits = match config.previous_visits {
+        Some(previous_visits) => previous_visits,
+        None => return Ok(yaml::Hash::new()),
+    };
+    let yaml_string = read_current_state_file(&previous_visits)?;
+
+    match parse_state(&yaml_string) {
+        Some(data_hash) => Ok(data_hash),
+        None => {
+            eprintln!(
+                "Warning: State {} could not be read, ignoring previous visits",
+                previous_visits.display()
+            );
+            Ok(yaml::Hash::new())
+        }
+    }
+}
+
+/// Saved visits by path, empty when `previous_visits` is not configured
+pub(crate) fn get_current_state(config: Config) -> Result<LinkedHashMap<PathBuf, Visit>> {
+    read_current_state_to_yamlmap(config).map(state_from_yaml)
+}
+
+/// Score bonus for each previously visited location, based on how often and how recently it was visited
+pub(crate) fn get_visit_bonuses(config: Config) -> Result<LinkedHashMap<PathBuf, i64>> {
+    let half_life = config.half_life();
+    let now = now();
+    Ok(get_current_state(config)?
+        .into_iter()
+        .map(|(path, visit)| {
+            let bonus = visit.frecency(now, half_life);
+            (path, bonus)
+        })
+        .collect())
+}
+
+/// Check if the saved visits are above max_total_weight or max_entries
+fn over_limits(config: &Config, state: &LinkedHashMap<PathBuf, Visit>) -> bool {
+    let total: i64 = state.values().map(|v| v.count).sum();
+    (config.max_total_weight > 0 && total > config.max_total_weight)
+        || (config.max_entries > 0 && state.len() > config.max_entries)
+}
+
+/// Check if it is time to look for missing directories again, checking every saved
+/// directory on each jump is slow with a long history or a hanging network mount
+fn missing_check_due(previous_visits: &Path) -> bool {
+    let checked = fs::metadata(state_sibling(previous_visits, ".checked"))
+        .and_then(|meta| meta.modified())
+        .ok();
+    checked
+        .and_then(|checked| checked.elapsed().ok())
+        .is_none_or(|elapsed| elapsed >= MISSING_CHECK_INTERVAL)
+}
+
+/// Remember that missing directories were just looked for
+fn missing_checked(previous_visits: &Path) {
+    // Failing only means checking again on the next visit
+    let _ = File::create(state_sibling(previous_visits, ".checked"));
+}
+
+/// Keep the number of saved visits bounded, never removing keep
+///
+/// With check_missing, visits to directories that no longer exist are removed, except on mounts
+/// the scan skips. All counts are reduced by 10% when their sum is above max_total_weight and the
+/// least valuable entries are dropped when there are more than max_entries. Zero turns off either limit.
+fn prune(
+    config: &Config,
+    state: &mut LinkedHashMap<PathBuf, Visit>,
+    keep: &Path,
+    check_missing: bool,
+) {
+    let mut remove: Vec<PathBuf> = vec![];
+
+    if check_missing {
+        let filesystems = Filesystems::new(config);
+        remove.extend(
+            state
+                .keys()
+                .filter(|location| {
+                    *location != keep
+                        && !filesystems.is_below_skipped(location)
+                        && !location.is_dir()
+                })
+                .cloned(),
+        );
+    }
+
+    let total: i64 = state.values().map(|v| v.count).sum();
+    if config.max_total_weight > 0 && total > config.max_total_weight {
+        for (location, visit) in state.iter_mut() {
+            visit.count = visit.count * 9 / 10;
+            if visit.count < 1 {
+                if location == keep {
+                    visit.count = 1;
+                } else {
+                    remove.push(location.clone());
+                }
+            }
+        }
+    }
+
+    for location in remove {
+        state.remove(&location);
+    }
+
+    if config.max_entries > 0 && state.len() > config.max_entries {
+        let half_life = config.half_life();
+        let now = now();
+        let mut by_value: Vec<(i64, PathBuf)> = state
+            .iter()
+            .filter(|(location, _)| *location != keep)
+            .map(|(location, visit)| (visit.frecency(now, half_life), location.clone()))
+            .collect();
+        by_value.sort();
+        let extra = state.len() - config.max_entries;
+        for (_, location) in by_value.into_iter().take(extra) {
+            state.remove(&location);
+        }
+    }
+}
+
+/// Location as given on the command line made into the key it is saved under.
+///
+/// Existing directories are canonicalized, missing ones are only made absolute against the
+/// current directory, so they can still be removed after being deleted.
+pub fn resolve_location(location: &Path) -> PathBuf {
+    fs::canonicalize(location).unwrap_or_else(|_| match std::env::current_dir() {
+        Ok(current) => current.join(location),
+        Err(_) => location.to_path_buf(),
+    })
+}
+
+/// Record a visit to location in `previous_visits`, if configured
+pub fn save(config: Config, location: PathBuf) -> Result<()> {
     let previous_visits = match config.clone().previous_visits {
-        None => return,
+        None => return Ok(()),
         Some(p) => p,
     };
-    let mut data_hash = read_current_state_to_yamlmap(config);
-    write_new_state_file(previous_visits, location, &mut data_hash);
+    let mut checked = false;
+    update_state(previous_visits.clone(), |state| {
+        add_visit(state, location.clone());
+        checked = config.prune_missing
+            && (over_limits(&config, state) || missing_check_due(&previous_visits));
+        prune(&config, state, &location, checked);
+    })?;
+    if checked {
+        missing_checked(&previous_visits);
+    }
+    Ok(())
+}
+
+/// Saved visits, most visited first
+pub fn sorted_visits(config: Config) -> Result<Vec<(PathBuf, Visit)>> {
+    let mut visits: Vec<(PathBuf, Visit)> = get_current_state(config)?.into_iter().collect();
+    visits.sort_by_key(|(_, visit)| std::cmp::Reverse(visit.count));
+    Ok(visits)
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Visits as a JSON array of `{"path": ..., "count": ..., "last_visit": ...}` objects.
+///
+/// JSON only holds text, so paths that are not UTF-8 are given lossily.
+pub fn visits_to_json(visits: &[(PathBuf, Visit)]) -> String {
+    let entries: Vec<String> = visits
+        .iter()
+        .map(|(path, visit)| {
+            format!(
+                "{{\"path\": {}, \"count\": {}, \"last_visit\": {}}}",
+                json_string(&path.to_string_lossy()),
+                visit.count,
+                visit.last_visit
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(", "))
+}
+
+/// Forget all visits to location, gives false if it was not visited
+pub fn remove(previous_visits: PathBuf, location: &Path) -> Result<bool> {
+    let mut removed = false;
+    update_state(previous_visits, |state| {
+        removed = state.remove(location).is_some();
+    })?;
+    Ok(removed)
+}
+
+/// Set the number of visits to location, adding it if it was not visited
+pub fn set_count(previous_visits: PathBuf, location: PathBuf, count: i64) -> Result<()> {
+    update_state(previous_visits, |state| {
+        let last_visit = state
+            .get(&location)
+            .map(|v| v.last_visit)
+            .unwrap_or_else(now);
+        state.insert(location, Visit { count, last_visit });
+    })
+}
+
+/// Forget visits to directories that no longer exist, gives the removed locations
+pub fn purge_missing(previous_visits: PathBuf) -> Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    update_state(previous_visits, |state| {
+        removed = state
+            .keys()
+            .filter(|location| !location.is_dir())
+            .cloned()
+            .collect();
+        for location in removed.iter() {
+            state.remove(location);
+        }
+    })?;
+    Ok(removed)
 }
 
 #[cfg(test)]
-pub fn write_yaml(path: PathBuf, contents: &[u8]) {
+pub(crate) fn write_yaml(path: PathBuf, contents: &[u8]) {
     let mut f = OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(path)
         .unwrap();
     f.write_all(contents).unwrap();
@@ -116,10 +509,18 @@ mod tests {
         let mut dir = env::temp_dir();
         dir.push(filename);
         fs::remove_file(dir.clone()).unwrap_or(());
+        fs::remove_file(state_sibling(&dir, ".checked")).unwrap_or(());
         config.previous_visits = Some(dir.clone());
         (config, dir)
     }
 
+    fn without_times(s: &str) -> String {
+        s.lines()
+            .filter(|l| !l.starts_with("  last_visit: "))
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
     #[test]
     fn test_get_handles_file_is_unwritable() {
         let mut config: Config = test_config();
@@ -127,8 +528,8 @@ mod tests {
         path.push("/unwritable/test.yml");
         config.previous_visits = Some(path);
 
-        let res = get_current_state(config);
-        let expected: LinkedHashMap<String, i64> = LinkedHashMap::new();
+        let res = get_current_state(config).unwrap();
+        let expected: LinkedHashMap<PathBuf, Visit> = LinkedHashMap::new();
         assert_eq!(res, expected);
     }
 
@@ -139,16 +540,16 @@ mod tests {
         path.push("/unwritable/test.yml");
         config.previous_visits = Some(path);
 
-        let location: String = String::from("something");
-        save(config, location);
+        let location = PathBuf::from("something");
+        let _ = save(config, location);
     }
 
     #[test]
     fn test_get_handles_file_is_none() {
         let mut config: Config = test_config();
         config.previous_visits = None;
-        let res = get_current_state(config);
-        let expected: LinkedHashMap<String, i64> = LinkedHashMap::new();
+        let res = get_current_state(config).unwrap();
+        let expected: LinkedHashMap<PathBuf, Visit> = LinkedHashMap::new();
         assert_eq!(res, expected);
     }
 
@@ -160,8 +561,8 @@ mod tests {
         write_yaml(dir.clone(), b"");
 
         config.previous_visits = Some(dir);
-        let res = get_current_state(config);
-        let expected: LinkedHashMap<String, i64> = LinkedHashMap::new();
+        let res = get_current_state(config).unwrap();
+        let expected: LinkedHashMap<PathBuf, Visit> = LinkedHashMap::new();
         assert_eq!(res, expected);
     }
 
@@ -174,30 +575,316 @@ mod tests {
             b"---\nsomething: 3",
         );
 
-        let res = get_current_state(config);
-        let mut expected: LinkedHashMap<String, i64> = LinkedHashMap::new();
-        expected.insert(String::from("something"), 3);
+        let res = get_current_state(config).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[Path::new("something")].count, 3);
+    }
+
+    #[test]
+    fn test_returns_visit_times_from_file() {
+        let (config, _) = setup("test_returns_visit_times_from_file.yml");
+
+        write_yaml(
+            config.clone().previous_visits.unwrap(),
+            b"---\nsomething:\n  count: 3\n  last_visit: 1000",
+        );
+
+        let res = get_current_state(config).unwrap();
+        let mut expected: LinkedHashMap<PathBuf, Visit> = LinkedHashMap::new();
+        let visit = Visit {
+            count: 3,
+            last_visit: 1000,
+        };
+        expected.insert(PathBuf::from("something"), visit);
         assert_eq!(res, expected);
     }
 
+    #[test]
+    fn test_frecency_decays() {
+        let visit = Visit {
+            count: 8,
+            last_visit: 1000,
+        };
+        assert_eq!(visit.frecency(1000, 100), 8);
+        assert_eq!(visit.frecency(1100, 100), 4);
+        assert_eq!(visit.frecency(1200, 100), 2);
+        assert_eq!(visit.frecency(1000 + 100 * 20, 100), 0);
+        assert_eq!(visit.frecency(5000, 0), 8);
+
+        let mut config = test_config();
+        config.half_life_days = u64::MAX;
+        assert_eq!(config.half_life(), u64::MAX);
+        assert_eq!(visit.frecency(u64::MAX, config.half_life()), 4);
+    }
+
+    #[test]
+    fn test_bonuses_prefer_recent_visits() {
+        let (mut config, _) = setup("test_bonuses_prefer_recent_visits.yml");
+        config.half_life_days = 7;
+
+        let contents = format!(
+            "---\nold:\n  count: 100\n  last_visit: {}\nrecent:\n  count: 10\n  last_visit: {}",
+            now() - 2 * 365 * 24 * 60 * 60,
+            now() - 60,
+        );
+        write_yaml(config.clone().previous_visits.unwrap(), contents.as_bytes());
+
+        let bonuses = get_visit_bonuses(config).unwrap();
+        assert_eq!(bonuses[Path::new("old")], 0);
+        assert_eq!(bonuses[Path::new("recent")], 10);
+    }
+
+    #[test]
+    fn test_save_concurrently() {
+        let (config, _) = setup("test_save_concurrently.yml");
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let config = config.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        save(config.clone(), PathBuf::from("something")).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let res = get_current_state(config).unwrap();
+        assert_eq!(res[Path::new("something")].count, 200);
+    }
+
+    #[test]
+    fn test_corrupt_file_is_backed_up() {
+        let mut config: Config = test_config();
+        let mut dir = env::temp_dir();
+        dir.push("fj_cache_corrupt");
+        fs::remove_dir_all(dir.clone()).unwrap_or(());
+        fs::create_dir_all(dir.clone()).unwrap();
+        let file = dir.join("visits.yml");
+        config.previous_visits = Some(file.clone());
+
+        write_yaml(file.clone(), b"---\nsomething: 1\n  garbage: [");
+        assert_eq!(
+            get_current_state(config.clone()).unwrap(),
+            LinkedHashMap::new()
+        );
+
+        save(config.clone(), PathBuf::from("new")).unwrap();
+        let res = get_current_state(config).unwrap();
+        assert_eq!(res.keys().collect::<Vec<&PathBuf>>(), vec!["new"]);
+
+        let backups: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("visits.yml.corrupt-"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+    }
+
+    #[test]
+    fn test_failed_write_removes_temporary_file() {
+        let mut dir = env::temp_dir();
+        dir.push("fj_cache_failed_write");
+        fs::remove_dir_all(&dir).unwrap_or(());
+        // A directory in the way of the state makes the final rename fail
+        let previous_visits = dir.join("visits.yml");
+        fs::create_dir_all(previous_visits.join("in_the_way")).unwrap();
+
+        assert!(write_state_file(&previous_visits, &yaml::Hash::new()).is_err());
+        let tmp = state_sibling(&previous_visits, &format!(".tmp{}", process::id()));
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn test_not_a_state_is_ignored() {
+        let (config, _) = setup("test_not_a_state_is_ignored.yml");
+        write_yaml(config.clone().previous_visits.unwrap(), b"just a string");
+        assert_eq!(get_current_state(config).unwrap(), LinkedHashMap::new());
+    }
+
+    #[test]
+    fn test_sorted_visits_and_json() {
+        let (config, _) = setup("test_sorted_visits_and_json.yml");
+        write_yaml(
+            config.clone().previous_visits.unwrap(),
+            b"---\nfew:\n  count: 1\n  last_visit: 10\n\"with \\\"quote\\\"\":\n  count: 5\n  last_visit: 20",
+        );
+
+        let visits = sorted_visits(config).unwrap();
+        let locations: Vec<&str> = visits.iter().map(|(l, _)| l.to_str().unwrap()).collect();
+        assert_eq!(locations, vec!["with \"quote\"", "few"]);
+        assert_eq!(
+            visits_to_json(&visits),
+            "[{\"path\": \"with \\\"quote\\\"\", \"count\": 5, \"last_visit\": 20}, \
+             {\"path\": \"few\", \"count\": 1, \"last_visit\": 10}]"
+        );
+        assert_eq!(visits_to_json(&[]), "[]");
+    }
+
+    #[test]
+    fn test_remove_and_set_count() {
+        let (config, dir) = setup("test_remove_and_set_count.yml");
+        write_yaml(
+            dir.clone(),
+            b"---\nsomething:\n  count: 3\n  last_visit: 10",
+        );
+
+        set_count(dir.clone(), PathBuf::from("something"), 7).unwrap();
+        set_count(dir.clone(), PathBuf::from("other"), 2).unwrap();
+        let state = get_current_state(config.clone()).unwrap();
+        assert_eq!(
+            state[Path::new("something")],
+            Visit {
+                count: 7,
+                last_visit: 10
+            }
+        );
+        assert_eq!(state[Path::new("other")].count, 2);
+
+        assert!(remove(dir.clone(), Path::new("something")).unwrap());
+        assert!(!remove(dir, Path::new("something")).unwrap());
+        let state = get_current_state(config).unwrap();
+        assert_eq!(state.keys().collect::<Vec<&PathBuf>>(), vec!["other"]);
+    }
+
+    #[test]
+    fn test_resolve_location() {
+        let current = std::env::current_dir().unwrap();
+        assert_eq!(
+            resolve_location(Path::new("test_configs/../test_configs")),
+            current.join("test_configs").canonicalize().unwrap()
+        );
+        assert_eq!(
+            resolve_location(Path::new("test_configs/gone")),
+            current.join("test_configs/gone")
+        );
+        assert_eq!(resolve_location(Path::new("/gone")), Path::new("/gone"));
+    }
+
+    #[test]
+    fn test_purge_missing() {
+        let (config, dir) = setup("test_purge_missing.yml");
+        write_yaml(
+            dir.clone(),
+            b"---\ntest_configs:\n  count: 3\n  last_visit: 10\n/nonexisting/dir:\n  count: 1\n  last_visit: 10",
+        );
+
+        let removed = purge_missing(dir).unwrap();
+        assert_eq!(removed, vec![String::from("/nonexisting/dir")]);
+        let state = get_current_state(config).unwrap();
+        assert_eq!(
+            state.keys().collect::<Vec<&PathBuf>>(),
+            vec!["test_configs"]
+        );
+    }
+
+    #[test]
+    fn test_save_prunes_missing() {
+        let (mut config, dir) = setup("test_save_prunes_missing.yml");
+        write_yaml(dir.clone(), b"---\n/nonexisting/dir: 3\ntest_configs: 1");
+
+        config.prune_missing = false;
+        save(config.clone(), PathBuf::from("/nonexisting/new")).unwrap();
+        assert_eq!(get_current_state(config.clone()).unwrap().len(), 3);
+
+        config.prune_missing = true;
+        save(config.clone(), PathBuf::from("/nonexisting/new")).unwrap();
+        let state = get_current_state(config).unwrap();
+        let locations: Vec<&PathBuf> = state.keys().collect();
+        assert_eq!(locations, vec!["test_configs", "/nonexisting/new"]);
+        assert_eq!(state[Path::new("/nonexisting/new")].count, 2);
+    }
+
+    #[test]
+    fn test_save_checks_missing_once_a_day_or_over_limits() {
+        let (config, dir) = setup("test_save_checks_missing_daily.yml");
+        write_yaml(dir.clone(), b"---\n/nonexisting/dir: 3\ntest_configs: 1");
+        save(config.clone(), PathBuf::from("test_configs")).unwrap();
+        assert_eq!(get_current_state(config.clone()).unwrap().len(), 1);
+
+        // Checked recently, so the missing directory is kept
+        save(config.clone(), PathBuf::from("/nonexisting/new")).unwrap();
+        save(config.clone(), PathBuf::from("test_configs")).unwrap();
+        assert_eq!(get_current_state(config.clone()).unwrap().len(), 2);
+
+        let mut config = config;
+        config.max_entries = 1;
+        save(config.clone(), PathBuf::from("test_configs")).unwrap();
+        let state = get_current_state(config).unwrap();
+        assert_eq!(
+            state.keys().collect::<Vec<&PathBuf>>(),
+            vec!["test_configs"]
+        );
+    }
+
+    #[test]
+    fn test_save_keeps_visits_on_skipped_mounts() {
+        let (mut config, dir) = setup("test_save_keeps_skipped_mounts.yml");
+        config.skip_mounts = vec![PathBuf::from("/nonexisting/mnt")];
+        write_yaml(dir, b"---\n/nonexisting/mnt/share: 3\n/nonexisting/dir: 1");
+
+        save(config.clone(), PathBuf::from("test_configs")).unwrap();
+        let state = get_current_state(config).unwrap();
+        let locations: Vec<&PathBuf> = state.keys().collect();
+        assert_eq!(locations, vec!["/nonexisting/mnt/share", "test_configs"]);
+    }
+
+    #[test]
+    fn test_save_ages_total_weight() {
+        let (mut config, dir) = setup("test_save_ages_total_weight.yml");
+        config.prune_missing = false;
+        config.max_total_weight = 100;
+        write_yaml(dir, b"---\nbig: 99\nsmall: 1");
+
+        save(config.clone(), PathBuf::from("new")).unwrap();
+        let state = get_current_state(config).unwrap();
+        let counts: Vec<(&str, i64)> = state
+            .iter()
+            .map(|(l, v)| (l.to_str().unwrap(), v.count))
+            .collect();
+        assert_eq!(counts, vec![("big", 89), ("new", 1)]);
+    }
+
+    #[test]
+    fn test_save_limits_entries() {
+        let (mut config, dir) = setup("test_save_limits_entries.yml");
+        config.prune_missing = false;
+        config.max_entries = 2;
+        let contents = format!(
+            "---\nold:\n  count: 50\n  last_visit: 0\nused:\n  count: 5\n  last_visit: {}\nrare: 1",
+            now()
+        );
+        write_yaml(dir, contents.as_bytes());
+
+        save(config.clone(), PathBuf::from("new")).unwrap();
+        let state = get_current_state(config).unwrap();
+        let locations: Vec<&PathBuf> = state.keys().collect();
+        assert_eq!(locations, vec!["used", "new"]);
+    }
+
     #[test]
     fn test_save_handles_file_is_none() {
         let mut config: Config = test_config();
         config.previous_visits = None;
-        let location: String = String::from("something");
-        save(config, location);
+        let location = PathBuf::from("something");
+        save(config, location).unwrap();
     }
 
     #[test]
     fn test_save_creates_file() {
         let (config, dir) = setup("test_creates_file.yml");
-        let location: String = String::from("something");
-        save(config, location);
+        let location = PathBuf::from("something");
+        save(config, location).unwrap();
 
-        let mut s = String::new();
-        read_current_state_file(dir, &mut s);
+        let s = read_current_state_file(&dir).unwrap();
 
-        assert_eq!(s, String::from("---\nsomething: 1"));
+        assert_eq!(
+            without_times(&s),
+            String::from("---\nsomething:\n  count: 1")
+        );
     }
 
     #[test]
@@ -210,13 +897,15 @@ mod tests {
         fs::remove_file(file.clone()).unwrap_or(());
         fs::remove_dir(dir.clone()).unwrap_or(());
         config.previous_visits = Some(file.clone());
-        let location: String = String::from("something");
-        save(config, location);
+        let location = PathBuf::from("something");
+        save(config, location).unwrap();
 
-        let mut s = String::new();
-        read_current_state_file(file.clone(), &mut s);
+        let s = read_current_state_file(&file).unwrap();
 
-        assert_eq!(s, String::from("---\nsomething: 1"));
+        assert_eq!(
+            without_times(&s),
+            String::from("---\nsomething:\n  count: 1")
+        );
     }
 
     #[test]
@@ -225,16 +914,18 @@ mod tests {
 
         write_yaml(
             config.clone().previous_visits.unwrap(),
-            b"---\nsomething: 1",
+            b"---\ntest_configs: 1",
         );
 
-        let location: String = String::from("new");
-        save(config, location);
+        let location = PathBuf::from("new");
+        save(config, location).unwrap();
 
-        let mut s = String::new();
-        read_current_state_file(dir, &mut s);
+        let s = read_current_state_file(&dir).unwrap();
 
-        assert_eq!(s, String::from("---\nsomething: 1\nnew: 1"));
+        assert_eq!(
+            without_times(&s),
+            String::from("---\ntest_configs:\n  count: 1\nnew:\n  count: 1")
+        );
     }
 
     #[test]
@@ -246,12 +937,55 @@ mod tests {
             b"---\nsomething: 1",
         );
 
-        let location: String = String::from("something");
-        save(config, location);
+        let location = PathBuf::from("something");
+        save(config, location).unwrap();
 
-        let mut s = String::new();
-        read_current_state_file(dir, &mut s);
+        let s = read_current_state_file(&dir).unwrap();
+
+        assert_eq!(
+            without_times(&s),
+            String::from("---\nsomething:\n  count: 2")
+        );
+    }
+
+    #[test]
+    fn test_save_keeps_non_utf8_paths() {
+        use std::os::unix::ffi::OsStrExt;
+        let (config, dir) = setup("test_non_utf8_paths.yml");
+        let location = PathBuf::from(std::ffi::OsStr::from_bytes(b"/caf\xe9"));
+        save(config.clone(), location.clone()).unwrap();
+
+        let s = read_current_state_file(&dir).unwrap();
+        assert!(s.contains("raw_path: 2f636166e9"));
+        let visits = sorted_visits(config).unwrap();
+        assert_eq!(visits[0].0, location);
+    }
+
+    #[test]
+    fn test_escaped_text_does_not_share_key_with_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+        let (mut config, _dir) = setup("test_escaped_key.yml");
+        config.prune_missing = false;
+        let raw = PathBuf::from(std::ffi::OsStr::from_bytes(b"/dir\xff"));
+        let text = PathBuf::from("/dir\\xff");
+        assert_ne!(location_key(&raw), location_key(&text));
+
+        save(config.clone(), raw.clone()).unwrap();
+        save(config.clone(), text.clone()).unwrap();
+        save(config.clone(), text.clone()).unwrap();
+
+        let visits = sorted_visits(config).unwrap();
+        assert_eq!(visits.len(), 2);
+        assert_eq!((&visits[0].0, visits[0].1.count), (&text, 2));
+        assert_eq!((&visits[1].0, visits[1].1.count), (&raw, 1));
+    }
+
+    #[test]
+    fn test_old_keys_with_backslash_are_read_as_is() {
+        let (config, dir) = setup("test_old_backslash_key.yml");
+        write_yaml(dir.clone(), b"---\n'/a\\b': 3");
 
-        assert_eq!(s, String::from("---\nsomething: 2"));
+        let visits = sorted_visits(config).unwrap();
+        assert_eq!(visits[0].0, PathBuf::from("/a\\b"));
     }
 }
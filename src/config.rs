@@ -1,107 +1,451 @@
 extern crate yaml_rust;
+use crate::error::{self, Error};
+use crate::ignores::{is_glob, GlobIgnores, GlobIgnoresBuilder};
 use std::collections::HashSet;
-use std::convert::TryInto;
+use std::convert::TryFrom;
 use std::fs::File;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 use std::path::{Path, PathBuf};
-use yaml_rust::{yaml, YamlLoader};
+use std::str::FromStr;
+use std::time::Duration;
+use yaml_rust::{Yaml, YamlLoader};
 
+/// How symlinks to directories are treated while scanning
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Symlinks {
+    /// Links are not candidates and never entered
+    Never,
+    /// Links are entered, every directory is still only scanned once
+    Follow,
+    /// Like Follow, but links inside a directory reached through a link are skipped
+    FollowOnce,
+}
+
+impl FromStr for Symlinks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Symlinks, String> {
+        match s {
+            "never" => Ok(Symlinks::Never),
+            "follow" => Ok(Symlinks::Follow),
+            "follow_once" => Ok(Symlinks::FollowOnce),
+            _ => Err(format!(
+                "Unknown symlinks {}, expected never, follow or follow_once",
+                s
+            )),
+        }
+    }
+}
+
+/// A directory to scan together with settings that only apply below it
+#[derive(Clone)]
+pub struct ScanRoot {
+    pub path: String,
+    /// Levels of directories below path to scan, None uses the global max_depth
+    pub max_depth: Option<usize>,
+    pub ignores: HashSet<String>,
+    pub ignore_globs: GlobIgnores,
+    /// Multiplier for the fuzzy score of directories below path
+    pub weight: f64,
+}
+
+/// Remove trailing slashes from path, except for the root directory
+fn trim_path(path: &str) -> &str {
+    match path.trim_end_matches('/') {
+        "" if path.starts_with('/') => "/",
+        trimmed => trimmed,
+    }
+}
+
+impl ScanRoot {
+    /// Root at path without settings of its own, so the top level ones apply
+    pub fn new(path: &str) -> ScanRoot {
+        ScanRoot {
+            path: String::from(trim_path(path)),
+            max_depth: None,
+            ignores: HashSet::new(),
+            ignore_globs: GlobIgnores::default(),
+            weight: 1.0,
+        }
+    }
+}
+
+/// Settings from the config file, see the README for what each of them does
 #[derive(Clone)]
 pub struct Config {
     pub ignores: HashSet<String>,
-    pub scan_root: String,
+    pub ignore_globs: GlobIgnores,
+    pub scan_roots: Vec<ScanRoot>,
+    /// Levels of directories below each root to scan, None scans everything
+    pub max_depth: Option<usize>,
+    pub symlinks: Symlinks,
+    pub one_filesystem: bool,
+    pub skip_fs_types: HashSet<String>,
+    pub skip_mounts: Vec<PathBuf>,
     pub num_threads: u8,
+    /// Time a scan may take before answering with the best match so far, None waits for the whole scan
+    pub scan_budget: Option<Duration>,
+    /// Stop scanning when a previously visited directory is named exactly like the pattern
+    pub early_exit: bool,
     pub previous_visits: Option<PathBuf>,
+    pub index: Option<PathBuf>,
+    pub index_max_age: u64,
+    pub daemon_socket: Option<PathBuf>,
+    pub half_life_days: u64,
+    pub max_entries: usize,
+    pub max_total_weight: i64,
+    pub prune_missing: bool,
+    pub respect_gitignore: bool,
+    pub scan_hidden: bool,
+    pub hidden_allow: HashSet<String>,
 }
 
-fn get_default_config_file() -> String {
-    let home = std::env::var("HOME").unwrap();
-    format!("{}/.fastjump.yml", home)
+fn home() -> error::Result<String> {
+    std::env::var("HOME").map_err(|_| Error::NoHome)
 }
 
-pub fn default_config() -> Config {
-    let scan_root = std::env::var("HOME").unwrap();
-    Config {
+fn get_default_config_file() -> error::Result<PathBuf> {
+    Ok(PathBuf::from(format!("{}/.fastjump.yml", home()?)))
+}
+
+impl Config {
+    /// Position in scan_roots of the root path is in, the innermost one if roots overlap
+    pub(crate) fn root_index(&self, path: &Path) -> Option<usize> {
+        self.scan_roots
+            .iter()
+            .enumerate()
+            .filter(|(_, root)| path.starts_with(&root.path))
+            .max_by_key(|(_, root)| root.path.len())
+            .map(|(i, _)| i)
+    }
+
+    pub(crate) fn root_of(&self, path: &Path) -> Option<&ScanRoot> {
+        self.root_index(path).map(|i| &self.scan_roots[i])
+    }
+
+    pub(crate) fn is_root(&self, path: &Path) -> bool {
+        self.scan_roots
+            .iter()
+            .any(|root| path == Path::new(&root.path))
+    }
+
+    /// Check if the entries of a directory depth levels below root are too deep to scan
+    pub(crate) fn at_max_depth(&self, root: &ScanRoot, depth: usize) -> bool {
+        root.max_depth
+            .or(self.max_depth)
+            .is_some_and(|max| depth >= max)
+    }
+
+    /// Seconds until the weight of a previous visit is halved, very long half lives saturate
+    pub(crate) fn half_life(&self) -> u64 {
+        self.half_life_days.saturating_mul(24 * 60 * 60)
+    }
+
+    /// Score multiplier for directories at path
+    pub(crate) fn weight(&self, path: &Path) -> f64 {
+        self.root_of(path).map_or(1.0, |root| root.weight)
+    }
+}
+
+/// Replace a leading `~` in path with home
+pub(crate) fn expand_home(path: &str, home: &str) -> String {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{}{}", home, rest),
+        _ => String::from(path),
+    }
+}
+
+/// Config used when there is no config file, scanning all of `HOME`
+pub(crate) fn default_config() -> error::Result<Config> {
+    let scan_root = home()?;
+    Ok(Config {
         ignores: HashSet::new(),
-        scan_root,
+        ignore_globs: GlobIgnores::default(),
+        scan_roots: vec![ScanRoot::new(&scan_root)],
+        max_depth: None,
+        symlinks: Symlinks::Follow,
+        one_filesystem: false,
+        skip_fs_types: HashSet::new(),
+        skip_mounts: vec![],
         num_threads: 1,
+        scan_budget: None,
+        early_exit: false,
         previous_visits: None,
-    }
+        index: None,
+        index_max_age: 3600,
+        daemon_socket: None,
+        half_life_days: 14,
+        max_entries: 1000,
+        max_total_weight: 9000,
+        prune_missing: true,
+        respect_gitignore: false,
+        scan_hidden: false,
+        hidden_allow: HashSet::new(),
+    })
 }
 
 #[cfg(test)]
-pub fn test_config() -> Config {
+pub(crate) fn test_config() -> Config {
     let ignores = HashSet::new();
     Config {
         ignores,
-        scan_root: String::from("test_configs"),
+        ignore_globs: GlobIgnores::default(),
+        scan_roots: vec![ScanRoot::new("test_configs")],
+        max_depth: None,
+        symlinks: Symlinks::Follow,
+        one_filesystem: false,
+        skip_fs_types: HashSet::new(),
+        skip_mounts: vec![],
         num_threads: 1,
+        scan_budget: None,
+        early_exit: false,
         previous_visits: None,
+        index: None,
+        index_max_age: 3600,
+        daemon_socket: None,
+        half_life_days: 14,
+        max_entries: 1000,
+        max_total_weight: 9000,
+        prune_missing: true,
+        respect_gitignore: false,
+        scan_hidden: false,
+        hidden_allow: HashSet::new(),
     }
 }
 
-fn read_config_from_file(mut file: File) -> Config {
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let datas = YamlLoader::load_from_str(contents.as_str()).unwrap();
+/// Value at key if it is set, as a list of strings
+fn read_strings<'a>(data: &'a Yaml, key: &str) -> Result<Vec<&'a str>, String> {
+    match &data[key] {
+        Yaml::BadValue | Yaml::Null => Ok(vec![]),
+        Yaml::Array(list) => list
+            .iter()
+            .map(|d| {
+                d.as_str()
+                    .ok_or_else(|| format!("{} must be a list of strings", key))
+            })
+            .collect(),
+        _ => Err(format!("{} must be a list of strings", key)),
+    }
+}
 
-    if !datas.is_empty() {
-        let data = &datas[0];
+fn read_str<'a>(data: &'a Yaml, key: &str) -> Result<Option<&'a str>, String> {
+    match &data[key] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::String(s) => Ok(Some(s)),
+        _ => Err(format!("{} must be a string", key)),
+    }
+}
 
-        let mut ignores = HashSet::new();
-        let default_ignores = yaml::Array::new();
-        let ignore_data = data["ignores"].as_vec().unwrap_or(&default_ignores);
+fn read_bool(data: &Yaml, key: &str) -> Result<Option<bool>, String> {
+    match &data[key] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Boolean(b) => Ok(Some(*b)),
+        _ => Err(format!("{} must be true or false", key)),
+    }
+}
 
-        for d in ignore_data {
-            ignores.insert(String::from(d.as_str().unwrap()));
-        }
+/// Value at key if it is set, as a whole number that fits in T
+fn read_number<T: TryFrom<i64>>(data: &Yaml, key: &str) -> Result<Option<T>, String> {
+    match &data[key] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(i) => T::try_from(*i)
+            .map(Some)
+            .map_err(|_| format!("{} is out of range: {}", key, i)),
+        _ => Err(format!("{} must be a whole number", key)),
+    }
+}
 
-        let default_root = ".";
-        let scan_root = data["scan_root"].as_str().unwrap_or(&default_root);
+fn read_path(data: &Yaml, key: &str) -> Result<Option<PathBuf>, String> {
+    Ok(read_str(data, key)?.map(PathBuf::from))
+}
 
-        let previous_visits: Option<PathBuf> = match data["previous_visits"].as_str() {
-            Some(s) => Some(PathBuf::from(s)),
-            None => None,
-        };
+/// Split ignores into plain folder names and glob patterns
+fn read_ignores(data: &Yaml, home: &str) -> Result<(HashSet<String>, GlobIgnores), String> {
+    let mut ignores = HashSet::new();
+    let mut ignore_globs = GlobIgnoresBuilder::default();
+    for pattern in read_strings(data, "ignores")? {
+        if !is_glob(pattern) {
+            ignores.insert(String::from(pattern));
+        } else {
+            ignore_globs
+                .add(pattern, home)
+                .map_err(|e| format!("Invalid ignore pattern {}: {}", pattern, e))?;
+        }
+    }
+    Ok((ignores, ignore_globs.build()))
+}
 
-        let num_threads: u8 = data["num_threads"]
-            .as_i64()
-            .unwrap_or(3)
-            .try_into()
-            .unwrap();
+/// Root given either as a path or as a hash with path and settings
+fn read_scan_root(data: &Yaml, home: &str) -> Result<ScanRoot, String> {
+    if let Some(path) = data.as_str() {
+        return Ok(ScanRoot::new(&expand_home(path, home)));
+    }
+    let path = read_str(data, "path")?.ok_or("Missing path for scan root")?;
+    let mut root = ScanRoot::new(&expand_home(path, home));
+    root.max_depth = read_number(data, "max_depth")?;
+    root.weight = match &data["weight"] {
+        Yaml::BadValue | Yaml::Null => 1.0,
+        Yaml::Integer(w) => *w as f64,
+        weight => weight
+            .as_f64()
+            .ok_or_else(|| format!("weight of scan root {} must be a number", path))?,
+    };
+    // Scores are multiplied by the weight, so others would hide or invert every match in the root
+    if !root.weight.is_finite() || root.weight <= 0.0 {
+        return Err(format!(
+            "weight of scan root {} must be a number above 0",
+            path
+        ));
+    }
+    let (ignores, ignore_globs) = read_ignores(data, home)?;
+    root.ignores = ignores;
+    root.ignore_globs = ignore_globs;
+    Ok(root)
+}
 
-        Config {
-            ignores,
-            scan_root: String::from(scan_root),
-            num_threads,
-            previous_visits,
+/// Roots from scan_roots or the single scan_root, without duplicates
+fn read_scan_roots(data: &Yaml, home: &str) -> Result<Vec<ScanRoot>, String> {
+    let mut roots: Vec<ScanRoot> = vec![];
+    let found = match &data["scan_roots"] {
+        Yaml::BadValue | Yaml::Null => {
+            let scan_root = read_str(data, "scan_root")?.unwrap_or(".");
+            vec![ScanRoot::new(&expand_home(scan_root, home))]
+        }
+        Yaml::Array(list) => list
+            .iter()
+            .map(|d| read_scan_root(d, home))
+            .collect::<Result<_, _>>()?,
+        _ => return Err(String::from("scan_roots must be a list")),
+    };
+    for root in found {
+        if !roots.iter().any(|r| r.path == root.path) {
+            roots.push(root);
         }
-    } else {
-        default_config()
     }
+    Ok(roots)
 }
 
-pub fn get_config_pb(maybe_config_file: Option<PathBuf>) -> Config {
-    match maybe_config_file {
-        Some(f) => get_config(Some(f.as_path())),
-        None => get_config(None),
+fn read_config_from_file(mut file: File) -> Result<Option<Config>, String> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .map_err(|e| e.to_string())?;
+    let datas = YamlLoader::load_from_str(contents.as_str()).map_err(|e| e.to_string())?;
+
+    let data = match datas.first() {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+
+    let home = std::env::var("HOME").unwrap_or_default();
+    let (ignores, ignore_globs) = read_ignores(data, &home)?;
+
+    let scan_roots = read_scan_roots(data, &home)?;
+
+    let max_depth: Option<usize> = read_number(data, "max_depth")?;
+
+    let symlinks = match read_str(data, "symlinks")? {
+        Some(symlinks) => Symlinks::from_str(symlinks)?,
+        None => Symlinks::Follow,
+    };
+
+    let one_filesystem: bool = read_bool(data, "one_filesystem")?.unwrap_or(false);
+
+    let skip_fs_types: HashSet<String> = read_strings(data, "skip_fs_types")?
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+    let skip_mounts: Vec<PathBuf> = read_strings(data, "skip_mounts")?
+        .into_iter()
+        .map(|d| PathBuf::from(trim_path(&expand_home(d, &home))))
+        .collect();
+
+    let previous_visits: Option<PathBuf> = read_path(data, "previous_visits")?;
+
+    let index: Option<PathBuf> = read_path(data, "index")?;
+
+    let index_max_age: u64 = read_number(data, "index_max_age")?.unwrap_or(3600);
+
+    let daemon_socket: Option<PathBuf> = read_path(data, "daemon_socket")?;
+
+    let half_life_days: u64 = read_number(data, "half_life_days")?.unwrap_or(14);
+    if half_life_days == 0 {
+        return Err(String::from("half_life_days must be at least 1"));
     }
+
+    let max_entries: usize = read_number(data, "max_entries")?.unwrap_or(1000);
+
+    let max_total_weight: i64 = read_number(data, "max_total_weight")?.unwrap_or(9000);
+
+    let prune_missing: bool = read_bool(data, "prune_missing")?.unwrap_or(true);
+
+    let respect_gitignore: bool = read_bool(data, "respect_gitignore")?.unwrap_or(false);
+
+    let scan_hidden: bool = read_bool(data, "scan_hidden")?.unwrap_or(false);
+
+    let hidden_allow: HashSet<String> = read_strings(data, "hidden_allow")?
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+    let num_threads: u8 = read_number(data, "num_threads")?.unwrap_or(3);
+
+    let scan_budget: Option<Duration> = read_number(data, "scan_budget_ms")?
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis);
+
+    let early_exit: bool = read_bool(data, "early_exit")?.unwrap_or(false);
+
+    Ok(Some(Config {
+        ignores,
+        ignore_globs,
+        scan_roots,
+        max_depth,
+        symlinks,
+        one_filesystem,
+        skip_fs_types,
+        skip_mounts,
+        num_threads,
+        scan_budget,
+        early_exit,
+        previous_visits,
+        index,
+        index_max_age,
+        daemon_socket,
+        half_life_days,
+        max_entries,
+        max_total_weight,
+        prune_missing,
+        respect_gitignore,
+        scan_hidden,
+        hidden_allow,
+    }))
 }
 
-pub fn get_config(maybe_config_file: Option<&Path>) -> Config {
-    let default_path = get_default_config_file();
-    let config_file = maybe_config_file.unwrap_or_else(|| Path::new(&default_path));
+/// Like `get_config` for an owned path
+pub fn get_config_pb(maybe_config_file: Option<PathBuf>) -> error::Result<Config> {
+    get_config(maybe_config_file.as_deref())
+}
 
-    match File::open(config_file) {
-        Ok(f) => read_config_from_file(f),
-        Err(e) => {
-            println!(
-                "Error: Failed to read config {}: {}",
-                config_file.to_str().unwrap(),
-                e
-            );
-            default_config()
-        }
+/// Read the config file, `~/.fastjump.yml` if none is given, falling back to
+/// `default_config` when it does not exist or is empty
+pub fn get_config(maybe_config_file: Option<&Path>) -> error::Result<Config> {
+    let config_file = match maybe_config_file {
+        Some(file) => file.to_path_buf(),
+        None => get_default_config_file()?,
+    };
+    let invalid = |message: String| Error::Config {
+        file: config_file.clone(),
+        message,
+    };
+
+    match File::open(&config_file) {
+        Ok(f) => match read_config_from_file(f).map_err(invalid)? {
+            Some(config) => Ok(config),
+            None => default_config(),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => default_config(),
+        Err(e) => Err(invalid(e.to_string())),
     }
 }
 
@@ -113,21 +457,21 @@ mod tests {
 
     #[test]
     fn test_no_config_file_existing() {
-        let config = get_config(Some(Path::new("nonexisting")));
+        let config = get_config(Some(Path::new("nonexisting"))).unwrap();
         assert_eq!(config.ignores, HashSet::new());
     }
 
     #[test]
     fn missing_default_file() {
         let config_file = PathBuf::from("/tmp/nonexistingthing");
-        let config = get_config_pb(Some(config_file));
+        let config = get_config_pb(Some(config_file)).unwrap();
         assert_eq!(config.num_threads, 1);
         assert_eq!(config.previous_visits, None);
     }
 
     #[test]
     fn get_none_config() {
-        get_config_pb(None);
+        get_config_pb(None).unwrap();
     }
 
     #[test]
@@ -136,48 +480,190 @@ mod tests {
         dir.push("file.yml");
 
         write_yaml(dir.clone(), b"");
-        let config = get_config_pb(Some(dir));
+        let config = get_config_pb(Some(dir)).unwrap();
         assert_eq!(config.num_threads, 1);
         assert_eq!(config.previous_visits, None);
     }
 
     #[test]
     fn test_parse_file() {
-        let config = get_config(Some(Path::new("test_configs/simple.yml")));
+        let config = get_config(Some(Path::new("test_configs/simple.yml"))).unwrap();
         let mut expected = HashSet::new();
         expected.insert(String::from("node_modules"));
         assert_eq!(config.ignores, expected);
-        assert_eq!(config.scan_root, String::from("test_configs"));
+        let root = "test_configs";
+        assert!(config
+            .ignore_globs
+            .is_match(root, Path::new("test_configs/a.egg-info")));
+        assert!(config
+            .ignore_globs
+            .is_match(root, Path::new("test_configs/work/project/vendor")));
+        assert!(!config
+            .ignore_globs
+            .is_match(root, Path::new("test_configs/node_modules")));
+        assert_eq!(config.scan_roots[0].path, String::from("test_configs"));
         assert_eq!(config.num_threads, 5);
         assert_eq!(
             config.previous_visits,
             Some(PathBuf::from("test_configs/previous.yml"))
         );
+        assert_eq!(config.index, Some(PathBuf::from("test_configs/index.txt")));
+        assert_eq!(config.index_max_age, 600);
+        assert_eq!(
+            config.daemon_socket,
+            Some(PathBuf::from("/tmp/fastjump.sock"))
+        );
+        assert_eq!(config.half_life_days, 30);
+        assert_eq!(config.max_entries, 200);
+        assert_eq!(config.max_total_weight, 0);
+        assert!(!config.prune_missing);
+        assert!(config.respect_gitignore);
+        assert_eq!(config.symlinks, Symlinks::FollowOnce);
+        assert!(config.one_filesystem);
+        assert!(config.skip_fs_types.contains("nfs4"));
+        assert_eq!(config.skip_mounts, vec![PathBuf::from("/mnt/share")]);
+        assert_eq!(config.scan_budget, Some(Duration::from_millis(250)));
+        assert!(config.early_exit);
+        assert!(config.scan_hidden);
+        assert!(config.hidden_allow.contains(".config"));
+    }
+
+    #[test]
+    fn test_parse_scan_roots() {
+        let config = get_config(Some(Path::new("test_configs/roots.yml"))).unwrap();
+        let home = std::env::var("HOME").unwrap();
+        let paths: Vec<&str> = config.scan_roots.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["test_configs", &format!("{}/src", home), "/data"]
+        );
+
+        let src = &config.scan_roots[1];
+        assert_eq!(src.max_depth, Some(3));
+        assert_eq!(src.weight, 1.5);
+        assert!(src.ignores.contains("vendor"));
+        assert!(src
+            .ignore_globs
+            .is_match(&src.path, &Path::new(&src.path).join("a.egg-info")));
+        assert_eq!(config.scan_roots[0].max_depth, None);
+        assert_eq!(config.max_depth, Some(6));
+        assert_eq!(config.scan_roots[0].weight, 1.0);
+        assert_eq!(config.scan_roots[2].weight, 2.0);
+    }
+
+    #[test]
+    fn test_root_of() {
+        let mut config = test_config();
+        config.scan_roots = vec![ScanRoot::new("/home/me"), ScanRoot::new("/home/me/src/")];
+        let root_of = |path: &str| config.root_of(Path::new(path)).map(|r| r.path.as_str());
+
+        assert_eq!(root_of("/home/me/src/a"), Some("/home/me/src"));
+        assert_eq!(root_of("/home/me/src"), Some("/home/me/src"));
+        assert_eq!(root_of("/home/me/srcs"), Some("/home/me"));
+        assert_eq!(root_of("/data"), None);
+        assert!(config.is_root(Path::new("/home/me/src")));
+        assert!(!config.is_root(Path::new("/home/me/src/a")));
     }
 
     #[test]
     fn test_missing_ignores() {
-        let config = get_config(Some(Path::new("test_configs/missing_ignores.yml")));
+        let config = get_config(Some(Path::new("test_configs/missing_ignores.yml"))).unwrap();
         assert_eq!(config.ignores, HashSet::new());
-        assert_eq!(config.scan_root, String::from("test_configs"));
+        assert_eq!(config.scan_roots[0].path, String::from("test_configs"));
     }
 
     #[test]
     fn test_missing_scan_root() {
-        let config = get_config(Some(Path::new("test_configs/missing_root.yml")));
+        let config = get_config(Some(Path::new("test_configs/missing_root.yml"))).unwrap();
         assert_eq!(config.ignores, HashSet::new());
-        assert_eq!(config.scan_root, String::from("."));
+        assert_eq!(config.scan_roots[0].path, String::from("."));
     }
 
     #[test]
     fn test_missing_threads() {
-        let config = get_config(Some(Path::new("test_configs/missing_root.yml")));
+        let config = get_config(Some(Path::new("test_configs/missing_root.yml"))).unwrap();
         assert_eq!(config.num_threads, 3);
     }
 
     #[test]
-    #[should_panic(expected = "TryFromIntError")]
+    fn test_missing_index() {
+        let config = get_config(Some(Path::new("test_configs/missing_root.yml"))).unwrap();
+        assert_eq!(config.index, None);
+        assert_eq!(config.index_max_age, 3600);
+        assert_eq!(config.daemon_socket, None);
+        assert_eq!(config.scan_budget, None);
+        assert!(!config.early_exit);
+        assert_eq!(config.half_life_days, 14);
+        assert_eq!(config.max_entries, 1000);
+        assert_eq!(config.max_total_weight, 9000);
+        assert!(config.prune_missing);
+        assert!(!config.respect_gitignore);
+        assert_eq!(config.max_depth, None);
+        assert_eq!(config.symlinks, Symlinks::Follow);
+        assert!(!config.one_filesystem);
+        assert!(config.skip_fs_types.is_empty());
+        assert!(config.skip_mounts.is_empty());
+        assert!(!config.scan_hidden);
+        assert!(config.hidden_allow.is_empty());
+    }
+
+    #[test]
     fn test_threads_too_large() {
-        get_config(Some(Path::new("test_configs/large_threads.yml")));
+        let error = get_config(Some(Path::new("test_configs/large_threads.yml")))
+            .err()
+            .unwrap();
+        assert_eq!(error.exit_code(), 78);
+        assert_eq!(
+            error.to_string(),
+            "Invalid config test_configs/large_threads.yml: num_threads is out of range: 90100000000000"
+        );
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let mut file = env::temp_dir();
+        file.push("fj_config_invalid_values.yml");
+        let invalid = |contents: &[u8]| {
+            write_yaml(file.clone(), contents);
+            match get_config(Some(&file)) {
+                Err(Error::Config { message, .. }) => message,
+                _ => panic!("Expected invalid config"),
+            }
+        };
+
+        assert_eq!(
+            invalid(b"max_depth: deep"),
+            "max_depth must be a whole number"
+        );
+        assert_eq!(
+            invalid(b"scan_hidden: 1"),
+            "scan_hidden must be true or false"
+        );
+        assert!(invalid(b"ignores: [\"a[\"]").starts_with("Invalid ignore pattern a["));
+        assert!(invalid(b"symlinks: sometimes").contains("sometimes"));
+        assert_eq!(
+            invalid(b"scan_roots:\n  - path: /a\n    weight: 0"),
+            "weight of scan root /a must be a number above 0"
+        );
+        assert_eq!(
+            invalid(b"scan_roots:\n  - path: /a\n    weight: -1.5"),
+            "weight of scan root /a must be a number above 0"
+        );
+        assert_eq!(
+            invalid(b"scan_roots:\n  - path: /a\n    weight: .inf"),
+            "weight of scan root /a must be a number above 0"
+        );
+        assert_eq!(
+            invalid(b"scan_roots:\n  - path: /a\n    weight: .nan"),
+            "weight of scan root /a must be a number above 0"
+        );
+        assert_eq!(
+            invalid(b"half_life_days: 0"),
+            "half_life_days must be at least 1"
+        );
+        assert_eq!(
+            invalid(b"half_life_days: -1"),
+            "half_life_days is out of range: -1"
+        );
     }
 }
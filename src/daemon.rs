@@ -0,0 +1,581 @@
+//! Background daemon keeping the directory tree in memory.
+//!
+//! The daemon listens on the unix socket configured as `daemon_socket`. Each
+//! connection carries exactly one request line and gets a response before the
+//! daemon closes the connection. Paths and patterns are escaped with `index::escape_line` and
+//! sent as their bytes, which need not be UTF-8.
+//!
+//! ```text
+//! ping                      -> pong
+//! match <limit> <pattern>   -> one line per result, best first: <score>\t<bonus>\t<path>
+//! anything else             -> error <message>
+//! ```
+//!
+//! A match without results gives an empty response.
+use crate::cache::get_visit_bonuses;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::fj_matcher::{match_paths, scan, walk_below, would_find, MatchResult};
+use crate::ignores::is_ignore_file;
+use crate::index::{below, escape_line, escape_path, unescape_line, unescape_path};
+use crate::mounts::Filesystems;
+use notify::event::ModifyKind;
+use notify::{recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long the client waits for the daemon before falling back to scanning
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+type Tree = Arc<Mutex<BTreeSet<PathBuf>>>;
+
+/// Remove everything below dir, gives the removed directories
+fn remove_below(dirs: &mut BTreeSet<PathBuf>, dir: &Path) -> Vec<PathBuf> {
+    let subtree: Vec<PathBuf> = dirs
+        .range::<Path, _>(below(dir))
+        .take_while(|path| path.starts_with(dir))
+        .cloned()
+        .collect();
+    for path in subtree.iter() {
+        dirs.remove(path);
+    }
+    subtree
+}
+
+fn remove_subtree(dirs: &mut BTreeSet<PathBuf>, dir: &Path) -> Vec<PathBuf> {
+    let mut removed = remove_below(dirs, dir);
+    if dirs.remove(dir) {
+        removed.push(dir.to_path_buf());
+    }
+    removed
+}
+
+/// Directories removed from and added to the tree by a change
+#[derive(Debug, Default, PartialEq)]
+struct Change {
+    removed: Vec<PathBuf>,
+    added: Vec<PathBuf>,
+}
+
+/// Update the tree after something happened to path
+///
+/// The file system is read without holding the lock, so queries are not held up meanwhile.
+fn apply_change(config: &Config, filesystems: &Filesystems, dirs: &Tree, path: &Path) -> Change {
+    if config.is_root(path) {
+        return Change::default();
+    }
+    // Changed ignore rules may hide or reveal anything below the directory
+    if is_ignore_file(path) {
+        return match path.parent() {
+            Some(parent) if config.is_root(parent) => {
+                let added = found_below_dir(config, parent);
+                let mut dirs = dirs.lock().unwrap();
+                let removed = remove_below(&mut dirs, parent);
+                dirs.extend(added.iter().cloned());
+                Change { removed, added }
+            }
+            Some(parent) => apply_change(config, filesystems, dirs, parent),
+            None => Change::default(),
+        };
+    }
+    // Removed, or a file which is never in the tree
+    if !path.is_dir() {
+        let removed = remove_subtree(&mut dirs.lock().unwrap(), path);
+        return Change {
+            removed,
+            added: vec![],
+        };
+    }
+
+    let parent_known = match path.parent() {
+        Some(parent) => config.is_root(parent) || dirs.lock().unwrap().contains(parent),
+        None => false,
+    };
+    let added = if parent_known && would_find(config, filesystems, path) {
+        let below = found_below_dir(config, path);
+        std::iter::once(path.to_path_buf()).chain(below).collect()
+    } else {
+        vec![]
+    };
+    // Renamed or recreated directories may have a different subtree
+    let mut dirs = dirs.lock().unwrap();
+    let removed = remove_subtree(&mut dirs, path);
+    dirs.extend(added.iter().cloned());
+    Change { removed, added }
+}
+
+/// Directories below dir, including the ones below roots inside dir
+fn found_below_dir(config: &Config, dir: &Path) -> Vec<PathBuf> {
+    let (tx, rx) = channel();
+    walk_below(config.clone(), dir, tx.clone());
+    // Roots inside dir are not entered by the walk as they are scanned on their own
+    for root in config.scan_roots.iter() {
+        let root_path = Path::new(&root.path);
+        if root_path != dir && root_path.starts_with(dir) {
+            walk_below(config.clone(), root_path, tx.clone());
+        }
+    }
+    drop(tx);
+    rx.iter().collect()
+}
+
+fn error_response(message: &[u8]) -> Vec<u8> {
+    let mut response = b"error ".to_vec();
+    response.extend(escape_line(message));
+    response.push(b'\n');
+    response
+}
+
+fn handle_request(config: &Config, dirs: &Tree, request: &[u8]) -> Vec<u8> {
+    if request == b"ping" {
+        return b"pong\n".to_vec();
+    }
+
+    let mut parts = request.splitn(3, |&b| b == b' ');
+    let command = parts.next();
+    let limit = parts
+        .next()
+        .and_then(|l| std::str::from_utf8(l).ok())
+        .map(|l| l.parse::<usize>());
+    // Patterns are matched against the lossy display of paths, so they are text as well
+    let pattern = parts
+        .next()
+        .map(|p| String::from_utf8_lossy(&unescape_line(p)).into_owned())
+        .unwrap_or_default();
+    match (command, limit) {
+        (Some(b"match"), Some(Ok(limit))) => {
+            let cache = match get_visit_bonuses(config.clone()) {
+                Ok(cache) => cache,
+                Err(e) => return error_response(e.to_string().as_bytes()),
+            };
+            let dirs = dirs.lock().unwrap();
+            let mut response = vec![];
+            for r in match_paths(config, dirs.iter(), &pattern, limit, &cache) {
+                response.extend(format!("{}\t{}\t", r.score, r.bonus).into_bytes());
+                response.extend(escape_path(&r.path));
+                response.push(b'\n');
+            }
+            response
+        }
+        _ => error_response(&[b"unknown request: ", request].concat()),
+    }
+}
+
+fn handle_connection(config: &Config, dirs: &Tree, stream: UnixStream) -> std::io::Result<()> {
+    // Clients give up after the same time, so there is no point in waiting longer
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = vec![];
+    reader.read_until(b'\n', &mut request)?;
+    let request = request.strip_suffix(b"\n").unwrap_or(&request);
+    let response = handle_request(config, dirs, request);
+    (&stream).write_all(&response)
+}
+
+/// Answer each connection on its own thread, so a stuck client does not hold up the others
+fn serve(config: Config, dirs: Tree, listener: UnixListener) {
+    let config = Arc::new(config);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let config = Arc::clone(&config);
+                let dirs = Arc::clone(&dirs);
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(&config, &dirs, stream) {
+                        eprintln!("Error: Failed to answer request: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Error: Failed to accept connection: {}", e),
+        }
+    }
+}
+
+/// Check if event can change the tree: entries created, removed or renamed, or ignore files
+/// changed in any way. Writes to other files and metadata changes can not.
+fn is_relevant(event: &EventKind, path: &Path) -> bool {
+    match event {
+        EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)) => {
+            true
+        }
+        EventKind::Modify(_) => is_ignore_file(path),
+        _ => false,
+    }
+}
+
+/// Watch the entries of each of dirs, gives the number of directories that could not be watched
+///
+/// Each directory in the tree is watched on its own instead of watching the roots recursively,
+/// so ignored trees like `node_modules` use none of the limited inotify watches.
+fn watch_dirs<'a, I: IntoIterator<Item = &'a PathBuf>>(
+    watcher: &mut dyn Watcher,
+    dirs: I,
+) -> usize {
+    dirs.into_iter()
+        .filter(|dir| watcher.watch(dir, RecursiveMode::NonRecursive).is_err())
+        .count()
+}
+
+fn warn_unwatched(count: usize) {
+    if count > 0 {
+        eprintln!(
+            "Warning: Failed to watch {} directories, changes in them are missed. Raise fs.inotify.max_user_watches or add ignores",
+            count
+        );
+    }
+}
+
+/// Keep the tree up to date while directories are created, removed or renamed
+fn watch(
+    config: Config,
+    dirs: Tree,
+    mut watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+) {
+    let filesystems = Filesystems::new(&config);
+    for event in events {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Error: Failed to watch for changes: {}", e);
+                continue;
+            }
+        };
+        for path in event.paths.iter() {
+            if !is_relevant(&event.kind, path) {
+                continue;
+            }
+            let change = apply_change(&config, &filesystems, &dirs, path);
+            for dir in change.removed.iter() {
+                // Fails for directories already gone, which lose their watch anyway
+                let _ = watcher.unwatch(dir);
+            }
+            warn_unwatched(watch_dirs(&mut watcher, change.added.iter()));
+        }
+    }
+}
+
+fn already_running() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::AddrInUse, "a daemon is already running")
+}
+
+/// Check if a daemon is listening on socket
+fn is_running(socket: &Path) -> bool {
+    socket.exists() && UnixStream::connect(socket).is_ok()
+}
+
+/// Bind socket, removing it first if it is left behind by a daemon no longer running
+fn bind(socket: &Path) -> std::io::Result<UnixListener> {
+    if is_running(socket) {
+        return Err(already_running());
+    }
+    if socket.exists() {
+        fs::remove_file(socket)?;
+    }
+    UnixListener::bind(socket)
+}
+
+/// Run the daemon until killed, only returns on failure to start
+pub fn run(config: Config) -> Result<()> {
+    let socket = match config.daemon_socket.clone() {
+        Some(socket) => socket,
+        None => return Err(Error::NotConfigured("daemon_socket")),
+    };
+    let listen_error =
+        |e: std::io::Error| Error::Daemon(format!("Failed to listen on socket: {}", e));
+    // Checked before the scan as well, which may take long
+    if is_running(&socket) {
+        return Err(listen_error(already_running()));
+    }
+
+    let (events_tx, events) = channel();
+    let mut watcher = recommended_watcher(events_tx)
+        .map_err(|e| Error::Daemon(format!("Failed to watch for changes: {}", e)))?;
+    // Start watching before scanning so nothing created meanwhile is missed
+    let roots: Vec<PathBuf> = config
+        .scan_roots
+        .iter()
+        .map(|r| PathBuf::from(&r.path))
+        .collect();
+    let mut unwatched = watch_dirs(&mut watcher, roots.iter());
+
+    let dirs: Tree = Arc::new(Mutex::new(BTreeSet::new()));
+    let (tx, rx) = channel();
+    let scan_config = config.clone();
+    let scanner = thread::spawn(move || scan(scan_config, tx));
+    for path in rx {
+        unwatched += watch_dirs(&mut watcher, Some(&path));
+        dirs.lock().unwrap().insert(path);
+    }
+    if let Ok(Err(e)) = scanner.join() {
+        return Err(e);
+    }
+    warn_unwatched(unwatched);
+
+    let watch_config = config.clone();
+    let watch_tree = Arc::clone(&dirs);
+    thread::spawn(move || watch(watch_config, watch_tree, watcher, events));
+
+    // Only listen once the tree is complete, until then clients scan on their own
+    let listener = bind(&socket).map_err(listen_error)?;
+    serve(config, dirs, listener);
+    Ok(())
+}
+
+fn parse_number(field: &[u8]) -> Option<i64> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+fn parse_result(line: &[u8]) -> Option<MatchResult> {
+    let mut parts = line.splitn(3, |&b| b == b'\t');
+    let score = parse_number(parts.next()?)?;
+    let bonus = parse_number(parts.next()?)?;
+    let path = unescape_path(parts.next()?);
+    Some(MatchResult { path, score, bonus })
+}
+
+fn send(socket: &Path, request: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.write_all(&[request, b"\n"].concat())?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    Ok(response
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_vec())
+        .collect())
+}
+
+/// Ask a running daemon for matches, gives None if there is no daemon to ask
+pub(crate) fn query(config: &Config, pattern: &str, limit: usize) -> Option<Vec<MatchResult>> {
+    let socket = config.daemon_socket.as_ref()?;
+    if !socket.exists() {
+        return None;
+    }
+    let mut request = format!("match {} ", limit).into_bytes();
+    request.extend(escape_line(pattern.as_bytes()));
+    let lines = send(socket, &request).ok()?;
+    lines.iter().map(|line| parse_result(line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{test_config, ScanRoot};
+    use crate::fj_matcher::walk;
+    use crate::ignores::IGNORE_FILE;
+    use std::env;
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    fn setup(name: &str, folders: Vec<&str>) -> (Config, PathBuf) {
+        let mut config: Config = test_config();
+        let mut dir = env::temp_dir();
+        dir.push(format!("{}_fj_daemon_tests", name));
+        fs::remove_dir_all(dir.clone()).unwrap_or(());
+        fs::create_dir_all(dir.join("root")).unwrap();
+        for folder in folders {
+            fs::create_dir_all(dir.join("root").join(folder)).unwrap();
+        }
+        config.scan_roots = vec![ScanRoot::new(dir.join("root").to_str().unwrap())];
+        config.daemon_socket = Some(dir.join("fastjump.sock"));
+        (config, dir)
+    }
+
+    fn tree(config: &Config) -> BTreeSet<PathBuf> {
+        let (tx, rx) = channel();
+        walk(config.clone(), tx).unwrap();
+        rx.iter().collect()
+    }
+
+    fn shared_tree(config: &Config) -> Tree {
+        Arc::new(Mutex::new(tree(config)))
+    }
+
+    #[test]
+    fn test_apply_change() {
+        let (config, dir) = setup("apply_change", vec!["a/b", "node_modules"]);
+        let mut config = config;
+        config.ignores.insert(String::from("node_modules"));
+        let dirs = shared_tree(&config);
+        let root = dir.join("root");
+        let filesystems = Filesystems::default();
+
+        fs::create_dir_all(root.join("new/deep")).unwrap();
+        fs::create_dir_all(root.join("node_modules/pkg")).unwrap();
+        fs::rename(root.join("a"), root.join("renamed")).unwrap();
+        for path in ["new", "node_modules/pkg", "a", "renamed"] {
+            apply_change(&config, &filesystems, &dirs, &root.join(path));
+        }
+
+        let dirs = dirs.lock().unwrap();
+        assert_eq!(*dirs, tree(&config));
+        assert!(dirs.contains(&root.join("renamed/b")));
+        assert!(!dirs.contains(&root.join("node_modules/pkg")));
+    }
+
+    #[test]
+    fn test_apply_change_gives_directories_to_watch() {
+        let (config, dir) = setup("apply_change_watch", vec!["a/b"]);
+        let dirs = shared_tree(&config);
+        let root = dir.join("root");
+        let filesystems = Filesystems::default();
+
+        fs::write(root.join("a/file"), "contents").unwrap();
+        let change = apply_change(&config, &filesystems, &dirs, &root.join("a/file"));
+        assert_eq!(change, Change::default());
+
+        fs::rename(root.join("a"), root.join("c")).unwrap();
+        let change = apply_change(&config, &filesystems, &dirs, &root.join("a"));
+        assert_eq!(change.removed, vec![root.join("a/b"), root.join("a")]);
+        let change = apply_change(&config, &filesystems, &dirs, &root.join("c"));
+        assert_eq!(change.added, vec![root.join("c"), root.join("c/b")]);
+    }
+
+    #[test]
+    fn test_only_structure_changes_are_relevant() {
+        use notify::event::{AccessKind, CreateKind, DataChange, MetadataKind, RenameMode};
+        let dir = Path::new("/src/project");
+        let ignore_file = dir.join(IGNORE_FILE);
+        let data = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+
+        assert!(is_relevant(&EventKind::Create(CreateKind::Folder), dir));
+        assert!(is_relevant(
+            &EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+            dir
+        ));
+        assert!(!is_relevant(&data, &dir.join("main.rs")));
+        assert!(!is_relevant(
+            &EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)),
+            dir
+        ));
+        assert!(!is_relevant(&EventKind::Access(AccessKind::Any), dir));
+        assert!(is_relevant(&data, &ignore_file));
+    }
+
+    #[test]
+    fn test_apply_change_respects_max_depth() {
+        let (mut config, dir) = setup("apply_change_depth", vec!["a"]);
+        let root = dir.join("root");
+        config.max_depth = Some(1);
+        config.skip_mounts = vec![root.join("mnt")];
+        let filesystems = Filesystems::new(&config);
+        let dirs = shared_tree(&config);
+
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        fs::create_dir_all(root.join("d/e")).unwrap();
+        fs::create_dir_all(root.join("mnt")).unwrap();
+        for path in ["a/b", "a/b/c", "d", "mnt"] {
+            apply_change(&config, &filesystems, &dirs, &root.join(path));
+        }
+
+        let dirs = dirs.lock().unwrap();
+        assert_eq!(*dirs, tree(&config));
+        assert_eq!(
+            *dirs,
+            vec![root.join("a"), root.join("d")].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_apply_change_to_ignore_file() {
+        let (config, dir) = setup("ignore_file", vec!["a/build/out", "b/build"]);
+        let dirs = shared_tree(&config);
+        let root = dir.join("root");
+        let filesystems = Filesystems::default();
+
+        fs::write(root.join("a").join(IGNORE_FILE), "build\n").unwrap();
+        apply_change(
+            &config,
+            &filesystems,
+            &dirs,
+            &root.join("a").join(IGNORE_FILE),
+        );
+        assert_eq!(*dirs.lock().unwrap(), tree(&config));
+        assert!(!dirs.lock().unwrap().contains(&root.join("a/build")));
+
+        fs::write(root.join(IGNORE_FILE), "b\n").unwrap();
+        apply_change(&config, &filesystems, &dirs, &root.join(IGNORE_FILE));
+        assert_eq!(*dirs.lock().unwrap(), tree(&config));
+        assert!(!dirs.lock().unwrap().contains(&root.join("b")));
+    }
+
+    #[test]
+    fn test_handle_request() {
+        let (config, dir) = setup("handle_request", vec!["projects/one"]);
+        let dirs: Tree = Arc::new(Mutex::new(tree(&config)));
+
+        assert_eq!(handle_request(&config, &dirs, b"ping"), b"pong\n");
+        assert!(handle_request(&config, &dirs, b"match x one").starts_with(b"error"));
+        assert!(handle_request(&config, &dirs, b"other").starts_with(b"error"));
+
+        let response = handle_request(&config, &dirs, b"match 1 one");
+        let result = parse_result(response.strip_suffix(b"\n").unwrap()).unwrap();
+        assert_eq!(result.path, dir.join("root/projects/one"));
+    }
+
+    #[test]
+    fn test_non_utf8_paths_are_sent_as_bytes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let (config, dir) = setup("non_utf8", vec![]);
+        let path = dir.join("root").join(OsStr::from_bytes(b"caf\xe9\nold"));
+        fs::create_dir_all(&path).unwrap();
+        let dirs: Tree = Arc::new(Mutex::new(tree(&config)));
+
+        let response = handle_request(&config, &dirs, b"match 1 caf");
+        let result = parse_result(response.strip_suffix(b"\n").unwrap()).unwrap();
+        assert_eq!(result.path, path);
+    }
+
+    #[test]
+    fn test_query_without_daemon() {
+        let (mut config, _dir) = setup("query_without", vec![]);
+        assert_eq!(query(&config, "one", 1), None);
+        config.daemon_socket = None;
+        assert_eq!(query(&config, "one", 1), None);
+    }
+
+    #[test]
+    fn test_daemon_answers_and_watches() {
+        let (config, dir) = setup("answers", vec!["projects/one"]);
+        let daemon_config = config.clone();
+        thread::spawn(move || run(daemon_config));
+
+        let socket = config.daemon_socket.clone().unwrap();
+        let started = Instant::now();
+        while send(&socket, b"ping").is_err() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // A client that never sends its request does not hold up the others
+        let _stuck = UnixStream::connect(&socket).unwrap();
+        let results = query(&config, "one", 1).unwrap();
+        assert_eq!(results[0].path, dir.join("root/projects/one"));
+
+        fs::create_dir_all(dir.join("root/projects/created")).unwrap();
+        let expected = dir.join("root/projects/created");
+        loop {
+            let results = query(&config, "created", 1).unwrap();
+            if results.first().map(|r| &r.path) == Some(&expected) {
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(run(config).is_err());
+    }
+}
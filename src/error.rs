@@ -0,0 +1,118 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Failures fastjump reports instead of answering
+#[derive(Debug)]
+pub enum Error {
+    /// The config file can not be read or has an invalid value
+    Config { file: PathBuf, message: String },
+    /// No config file was given and `HOME` is not set, so there is nowhere to look for one
+    NoHome,
+    /// A setting the command needs is missing from the config file
+    NotConfigured(&'static str),
+    /// The previous visits file can not be read or written
+    State { file: PathBuf, source: io::Error },
+    /// The index file can not be written
+    Index { file: PathBuf, source: io::Error },
+    /// No scan root can be read
+    Traversal { path: PathBuf, source: io::Error },
+    /// History from another directory jumper can not be imported
+    Import { file: PathBuf, message: String },
+    /// The daemon can not serve or watch
+    Daemon(String),
+    /// The interactive picker can not use the terminal
+    Terminal(io::Error),
+    /// The command line asks for something that can not be done
+    Usage(String),
+}
+
+/// Result of anything that can fail with an `Error`
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Process exit code for the error, following the conventions of sysexits.h
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 64,
+            Error::Import { .. } => 65,
+            Error::Traversal { .. } => 66,
+            Error::Daemon(_) => 69,
+            Error::State { .. } | Error::Index { .. } | Error::Terminal(_) => 74,
+            Error::Config { .. } | Error::NoHome | Error::NotConfigured(_) => 78,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config { file, message } => {
+                write!(f, "Invalid config {}: {}", file.display(), message)
+            }
+            Error::NoHome => write!(f, "HOME is not set, pass --config to choose a config file"),
+            Error::NotConfigured(key) => {
+                write!(f, "No {} configured, set `{}` in the config file", key, key)
+            }
+            Error::State { file, source } => {
+                write!(f, "Failed to access state {}: {}", file.display(), source)
+            }
+            Error::Index { file, source } => {
+                write!(f, "Failed to write index {}: {}", file.display(), source)
+            }
+            Error::Traversal { path, source } => {
+                write!(f, "Failed to scan {}: {}", path.display(), source)
+            }
+            Error::Import { file, message } => {
+                write!(f, "Failed to import {}: {}", file.display(), message)
+            }
+            Error::Daemon(message) => write!(f, "{}", message),
+            Error::Terminal(source) => {
+                write!(f, "Failed to run interactive picker: {}", source)
+            }
+            Error::Usage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::State { source, .. }
+            | Error::Index { source, .. }
+            | Error::Traversal { source, .. }
+            | Error::Terminal(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_are_distinct_per_kind() {
+        let io_error = || io::Error::from(io::ErrorKind::NotFound);
+        let config = Error::Config {
+            file: PathBuf::from("a.yml"),
+            message: String::from("bad"),
+        };
+        let state = Error::State {
+            file: PathBuf::from("visits.yml"),
+            source: io_error(),
+        };
+        let traversal = Error::Traversal {
+            path: PathBuf::from("/data"),
+            source: io_error(),
+        };
+        assert_eq!(config.exit_code(), 78);
+        assert_eq!(state.exit_code(), 74);
+        assert_eq!(traversal.exit_code(), 66);
+        assert_eq!(config.to_string(), "Invalid config a.yml: bad");
+        assert_eq!(
+            Error::NotConfigured("index").to_string(),
+            "No index configured, set `index` in the config file"
+        );
+    }
+}
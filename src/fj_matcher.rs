@@ -1,7 +1,14 @@
-use crate::cache::get_current_state;
+use crate::cache::get_visit_bonuses;
 #[cfg(test)]
 use crate::config::test_config;
-use crate::config::Config;
+use crate::config::{Config, ScanRoot, Symlinks};
+use crate::daemon;
+use crate::error::{Error, Result};
+use crate::ignores::IgnoreStack;
+use crate::index::load_fresh;
+use crate::mounts::Filesystems;
+use crossbeam_deque::{Injector, Stealer, Worker};
+use crossbeam_utils::Backoff;
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
 use linked_hash_map::LinkedHashMap;
@@ -9,129 +16,807 @@ use linked_hash_map::LinkedHashMap;
 use rand::distributions::Alphanumeric;
 #[cfg(test)]
 use rand::Rng;
-use std::collections::VecDeque;
+use std::collections::HashSet;
 #[cfg(test)]
 use std::env;
+use std::ffi::OsString;
 use std::fs;
+use std::iter;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(test)]
+use std::sync::mpsc::channel;
+use std::sync::mpsc::{sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-fn match_worker(
-    config: Config,
-    pattern: String,
-    cache: LinkedHashMap<String, i64>,
-    arc_dirs: Arc<Mutex<VecDeque<String>>>,
-    tx: Sender<(i64, String)>,
+/// Results with a lower total score are not good enough to jump to
+pub(crate) const MIN_SCORE: i64 = 10;
+
+/// A directory matching the pattern together with the parts of its score
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchResult {
+    /// Path to the matched directory, unescaped
+    pub path: PathBuf,
+    /// Score from fuzzy matching the path against the pattern
+    pub score: i64,
+    /// Extra score given for previous visits to the directory
+    pub bonus: i64,
+}
+
+impl MatchResult {
+    /// Score the results are ranked by
+    pub fn total(&self) -> i64 {
+        self.score + self.bonus
+    }
+}
+
+/// A directory found while scanning, see `stream`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub result: MatchResult,
+    /// Levels below the scan root the directory was found from
+    pub depth: usize,
+}
+
+/// Candidates the scanning threads may get ahead of the reader of a stream
+const STREAM_BUFFER: usize = 64;
+
+/// Longest an idle scanning thread sleeps before looking for directories again
+const IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Insert result keeping results sorted best first and at most limit long.
+/// Results with equal score keep the order they were found in.
+pub(crate) fn insert_ranked(results: &mut Vec<MatchResult>, result: MatchResult, limit: usize) {
+    let total = result.total();
+    let position = results
+        .iter()
+        .position(|r| r.total() < total)
+        .unwrap_or(results.len());
+    if position < limit {
+        results.insert(position, result);
+        results.truncate(limit);
+    }
+}
+
+/// Score path against pattern, gives None if the path should not be considered at all.
+///
+/// Paths that are not UTF-8 are matched by their lossy display.
+pub(crate) fn score_path(
+    skim_matcher: &SkimMatcherV2,
+    pattern: &str,
+    cache: &LinkedHashMap<PathBuf, i64>,
+    path: &Path,
+    weight: f64,
+) -> Option<MatchResult> {
+    let score = skim_matcher
+        .fuzzy_match(&path.to_string_lossy(), pattern)
+        .unwrap_or(0);
+    let score = (score as f64 * weight).round() as i64;
+    let bonus = *cache.get(path).unwrap_or(&0);
+
+    if score + bonus > 0 {
+        Some(MatchResult {
+            path: path.to_path_buf(),
+            score,
+            bonus,
+        })
+    } else {
+        None
+    }
+}
+
+/// A directory left to scan
+struct Pending {
+    path: PathBuf,
+    /// Position of the root the directory is below in config.scan_roots
+    root: usize,
+    /// Levels below the root
+    depth: usize,
+    /// Rules for the entries of the directory
+    ignores: IgnoreStack,
+    /// Reached through a symlink
+    via_link: bool,
+    /// Device id of the directory, 0 when devices are not tracked
+    dev: u64,
+}
+
+/// Directories left to scan, shared by the scanning threads.
+///
+/// Each thread has its own deque of directories and steals from the others when it runs out.
+/// The threads finish when no directory is queued or being read by any of them.
+struct ScanQueue {
+    /// Directories to start from, taken by whichever thread gets there first
+    injector: Injector<Pending>,
+    stealers: Vec<Stealer<Pending>>,
+    /// Directories queued or being read, new ones can only appear while this is above 0
+    outstanding: AtomicUsize,
+    filesystems: Filesystems,
+    /// Device and inode of every directory reached through a symlink, None when not following symlinks
+    visited: Option<Mutex<HashSet<(u64, u64)>>>,
+    /// Set when the scanning threads should finish without emptying the queue
+    stop: AtomicBool,
+    /// Time the scan should stop at, None scans everything
+    deadline: Option<Instant>,
+    /// Threads waiting in `wait` for directories to be queued
+    sleepers: AtomicUsize,
+    idle: Mutex<()>,
+    wake: Condvar,
+}
+
+type Queue = Arc<ScanQueue>;
+
+impl ScanQueue {
+    /// Setup queue of directories to scan, starting from dirs, with a deque for each thread
+    fn new<'a, I: IntoIterator<Item = &'a Path>>(
+        config: &Config,
+        dirs: I,
+        deadline: Option<Instant>,
+    ) -> (Queue, Vec<Worker<Pending>>) {
+        let filesystems = Filesystems::new(config);
+        let dirs = dirs
+            .into_iter()
+            .filter_map(|d| pending(config, &filesystems, d))
+            .collect();
+        ScanQueue::with_pending(config, filesystems, dirs, deadline)
+    }
+
+    fn with_pending(
+        config: &Config,
+        filesystems: Filesystems,
+        dirs: Vec<Pending>,
+        deadline: Option<Instant>,
+    ) -> (Queue, Vec<Worker<Pending>>) {
+        let visited = match config.symlinks {
+            Symlinks::Never => None,
+            Symlinks::Follow | Symlinks::FollowOnce => Some(Mutex::new(HashSet::new())),
+        };
+        // First in first out keeps the scan breadth first, so shallow directories are found
+        // before a time budget runs out
+        let workers: Vec<Worker<Pending>> = (0..config.num_threads.max(1))
+            .map(|_| Worker::new_fifo())
+            .collect();
+        let injector = Injector::new();
+        let outstanding = AtomicUsize::new(dirs.len());
+        for dir in dirs {
+            injector.push(dir);
+        }
+        let queue = Arc::new(ScanQueue {
+            injector,
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            outstanding,
+            filesystems,
+            visited,
+            stop: AtomicBool::new(false),
+            deadline,
+            sleepers: AtomicUsize::new(0),
+            idle: Mutex::new(()),
+            wake: Condvar::new(),
+        });
+        (queue, workers)
+    }
+
+    /// Make the scanning threads finish after their current directory entry
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.wake_all();
+    }
+
+    /// Check if the scan was stopped or has run past its deadline
+    fn stopped(&self) -> bool {
+        if self.stop.load(Ordering::Relaxed) {
+            return true;
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.stop();
+            return true;
+        }
+        false
+    }
+
+    /// Next directory for the thread owning local, gives None when every deque is empty
+    fn pop(&self, local: &Worker<Pending>) -> Option<Pending> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(|steal| steal.success())
+        })
+    }
+
+    fn push(&self, local: &Worker<Pending>, pending: Pending) {
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+        local.push(pending);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _idle = self.idle.lock().unwrap();
+            self.wake.notify_one();
+        }
+    }
+
+    /// Mark a directory taken with pop as done
+    fn done(&self) {
+        if self.outstanding.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.wake_all();
+        }
+    }
+
+    fn wake_all(&self) {
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _idle = self.idle.lock().unwrap();
+            self.wake.notify_all();
+        }
+    }
+
+    fn has_queued(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|s| !s.is_empty())
+    }
+
+    /// Sleep until a directory is queued or the scan is over, so idle threads leave the CPU to
+    /// the ones reading directories. The timeout covers a wake up racing with going to sleep.
+    fn wait(&self) {
+        let idle = self.idle.lock().unwrap();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        if !self.has_queued() && !self.finished() && !self.stop.load(Ordering::Relaxed) {
+            let _ = self.wake.wait_timeout(idle, IDLE_TIMEOUT).unwrap();
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Check if every directory has been scanned, an empty deque alone does not mean that as
+    /// another thread may be about to push the entries of the directory it is reading
+    fn finished(&self) -> bool {
+        self.outstanding.load(Ordering::SeqCst) == 0
+    }
+
+    /// Check if the directory at path has not been found before through another link.
+    ///
+    /// This breaks symlink cycles outside the scan roots and skips duplicate links.
+    fn first_visit(&self, path: &Path) -> bool {
+        let visited = match &self.visited {
+            Some(visited) => visited,
+            None => return true,
+        };
+        match fs::metadata(path) {
+            Ok(meta) => visited.lock().unwrap().insert((meta.dev(), meta.ino())),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Check if path is a directory that should be scanned and matched.
+///
+/// Root is the scan root path is below and ignores are the rules for the parent of path.
+pub(crate) fn is_candidate(
+    config: &Config,
+    root: &ScanRoot,
+    ignores: &IgnoreStack,
+    path: &Path,
+) -> bool {
+    if path.is_symlink() && !follow_link(config, path) {
+        return false;
+    }
+    if !path.is_dir() {
+        return false;
+    };
+
+    let folder = path.file_name().unwrap_or_default();
+    // Names from the config are UTF-8, so a folder that is not never matches them
+    let listed = |names: &HashSet<String>| folder.to_str().is_some_and(|f| names.contains(f));
+
+    // Parents are checked before their entries, so only the folder itself is left to check
+    if folder.as_bytes().starts_with(b".") && !config.scan_hidden && !listed(&config.hidden_allow) {
+        return false;
+    };
+
+    !listed(&config.ignores)
+        && !listed(&root.ignores)
+        && !config.ignore_globs.is_match(&root.path, path)
+        && !root.ignore_globs.is_match(&root.path, path)
+        && !ignores.is_ignored(path, true)
+}
+
+/// Check if the symlink at path should be followed.
+///
+/// Links to directories inside a scan root are not, those are found through their real path.
+/// Neither are links to a root or one of its parents, which would scan the root again below itself.
+fn follow_link(config: &Config, path: &Path) -> bool {
+    if config.symlinks == Symlinks::Never {
+        return false;
+    }
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => return false,
+    };
+    !config.scan_roots.iter().any(|root| {
+        fs::canonicalize(&root.path)
+            .is_ok_and(|root_path| target.starts_with(&root_path) || root_path.starts_with(&target))
+    })
+}
+
+/// Walk directories from the shared queue, calling found for each directory that is not ignored
+fn scan_worker<F: FnMut(&Path, &ScanRoot, usize)>(
+    config: &Config,
+    arc_dirs: Queue,
+    local: Worker<Pending>,
+    mut found: F,
 ) {
-    let skim_matcher = SkimMatcherV2::default();
-    let mut best_s = 0;
-    let mut best_res = String::from("");
+    let backoff = Backoff::new();
     loop {
-        let mut dirs = arc_dirs.lock().unwrap();
-        let maybe_dir = match dirs.pop_front() {
-            Some(path_str) => {
-                drop(dirs);
-                let current_path: &Path = Path::new(path_str.as_str());
-                fs::read_dir(current_path)
+        if arc_dirs.stopped() {
+            break;
+        }
+        match arc_dirs.pop(&local) {
+            Some(pending) => {
+                scan_dir(config, &arc_dirs, &local, pending, &mut found);
+                arc_dirs.done();
+                backoff.reset();
             }
-            None => break,
-        };
+            None if arc_dirs.finished() => break,
+            // Other threads are still reading, spin briefly for their entries before sleeping
+            None if backoff.is_completed() => arc_dirs.wait(),
+            None => backoff.snooze(),
+        }
+    }
+}
+
+/// Call found for the entries of the directory pending and queue the ones to scan further
+fn scan_dir<F: FnMut(&Path, &ScanRoot, usize)>(
+    config: &Config,
+    arc_dirs: &ScanQueue,
+    local: &Worker<Pending>,
+    pending: Pending,
+    found: &mut F,
+) {
+    let root = &config.scan_roots[pending.root];
+    let depth = pending.depth + 1;
+
+    let dir = match fs::read_dir(&pending.path) {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
 
-        let dir = match maybe_dir {
-            Ok(dir) => dir,
+    for thing in dir {
+        if arc_dirs.stopped() {
+            break;
+        }
+        let path: PathBuf = match thing {
+            Ok(de) => de.path(),
             Err(_) => break,
         };
+        if !found_in(config, &arc_dirs.filesystems, &pending, &path) {
+            continue;
+        }
+        // Other roots are scanned with their own settings
+        if config.is_root(&path) {
+            found(&path, root, depth);
+            continue;
+        }
 
-        for thing in dir {
-            let path: PathBuf = match thing {
-                Ok(de) => de.path(),
-                Err(_) => break,
-            };
-            if !path.is_dir() {
-                continue;
-            };
+        let link = path.is_symlink();
+        if link && pending.via_link && config.symlinks == Symlinks::FollowOnce {
+            continue;
+        }
+        if (link || pending.via_link) && !arc_dirs.first_visit(&path) {
+            continue;
+        }
+        found(&path, root, depth);
 
-            let path_str = path.to_str().unwrap();
-            let path_string: String = String::from(path_str);
+        if config.at_max_depth(root, depth) {
+            continue;
+        }
+        let dev = match arc_dirs.filesystems.enter(pending.dev, &path) {
+            Some(dev) => dev,
+            None => continue,
+        };
+        let child = Pending {
+            ignores: pending.ignores.enter(config, &path),
+            path,
+            root: pending.root,
+            depth,
+            via_link: pending.via_link || link,
+            dev,
+        };
+        arc_dirs.push(local, child);
+    }
+}
 
-            if path_string.contains("/.") {
-                continue;
-            };
+fn stream_worker(
+    config: Config,
+    pattern: String,
+    cache: Arc<LinkedHashMap<PathBuf, i64>>,
+    arc_dirs: Queue,
+    local: Worker<Pending>,
+    tx: SyncSender<Candidate>,
+) {
+    let skim_matcher = SkimMatcherV2::default();
+    scan_worker(&config, arc_dirs, local, |path, root, depth| {
+        if let Some(result) = score_path(&skim_matcher, &pattern, &cache, path, root.weight) {
+            // The receiver hanging up means the stream was dropped, which also stops the scan
+            let _ = tx.send(Candidate { result, depth });
+        }
+    });
+}
+
+/// Check if result is good enough to stop scanning for better ones: a previously visited
+/// directory named exactly like the pattern
+fn is_strong_match(pattern: &str, result: &MatchResult) -> bool {
+    result.bonus > 0 && result.path.file_name().is_some_and(|name| name == pattern)
+}
 
-            let folder: &str = path_str.split('/').last().unwrap_or("");
+/// Queue entry for dir, gives None if dir is not in a scan root, too deep to scan or
+/// on a file system that should not be scanned
+fn pending(config: &Config, filesystems: &Filesystems, path: &Path) -> Option<Pending> {
+    let root = config.root_index(path)?;
+    let root_path = Path::new(&config.scan_roots[root].path);
+    let depth = path.strip_prefix(root_path).ok()?.components().count();
+    if config.at_max_depth(&config.scan_roots[root], depth) {
+        return None;
+    }
+    if filesystems.is_skipped(path) {
+        return None;
+    }
+    let dev = match path.parent() {
+        Some(parent) if depth > 0 => filesystems.enter(filesystems.device(parent), path)?,
+        _ => filesystems.device(path),
+    };
+    Some(Pending {
+        path: path.to_path_buf(),
+        root,
+        depth,
+        ignores: IgnoreStack::load(config, &config.scan_roots[root], path),
+        via_link: false,
+        dev,
+    })
+}
 
-            if config.ignores.contains(folder) {
-                continue;
-            }
+/// Check if the scan reports path, an entry of the directory pending
+fn found_in(config: &Config, filesystems: &Filesystems, pending: &Pending, path: &Path) -> bool {
+    !filesystems.is_skipped(path)
+        && is_candidate(
+            config,
+            &config.scan_roots[pending.root],
+            &pending.ignores,
+            path,
+        )
+}
 
-            let mut score = skim_matcher.fuzzy_match(&path_str, &pattern).unwrap_or(0);
+/// Entries of dir a scan would report, or None when a scan does not read dir at all
+/// because it is too deep or on a file system that is not scanned
+///
+/// Keeps updates of single directories in line with full scans.
+pub(crate) fn found_below(
+    config: &Config,
+    filesystems: &Filesystems,
+    dir: &Path,
+) -> Option<Vec<PathBuf>> {
+    let pending = pending(config, filesystems, dir)?;
+    let entries = fs::read_dir(dir).ok()?;
+    Some(
+        entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| found_in(config, filesystems, &pending, path))
+            .collect(),
+    )
+}
 
-            if cache.contains_key(&path_string) {
-                score += cache[&path_string];
-            }
+/// Check if a scan would report path, see `found_below`
+pub(crate) fn would_find(config: &Config, filesystems: &Filesystems, path: &Path) -> bool {
+    path.parent()
+        .and_then(|parent| pending(config, filesystems, parent))
+        .is_some_and(|pending| found_in(config, filesystems, &pending, path))
+}
 
-            if score > best_s {
-                best_s = score;
-                best_res = path_string;
-            }
+fn root_paths(config: &Config) -> impl Iterator<Item = &Path> {
+    config.scan_roots.iter().map(|root| Path::new(&root.path))
+}
 
-            let mut dirs = arc_dirs.lock().unwrap();
-            dirs.push_back(String::from(path_str));
-            drop(dirs);
+/// Warn about scan roots that can not be read, like an unmounted drive, and fail only if none
+/// can be. Directories below a root that can not be read are skipped silently.
+pub(crate) fn check_roots(config: &Config) -> Result<()> {
+    let mut errors = vec![];
+    for root in &config.scan_roots {
+        if let Err(source) = fs::read_dir(&root.path) {
+            errors.push(Error::Traversal {
+                path: PathBuf::from(&root.path),
+                source,
+            });
         }
     }
-    tx.send((best_s, best_res)).unwrap();
+    if !errors.is_empty() && errors.len() == config.scan_roots.len() {
+        return Err(errors.remove(0));
+    }
+    for error in errors {
+        eprintln!("Warning: {}", error);
+    }
+    Ok(())
 }
 
-pub fn matcher(config: Config, pattern: String) -> String {
-    let cache: LinkedHashMap<String, i64> = get_current_state(config.clone());
+/// Send every directory below the scan roots to tx as it is found, returns when the scan is done
+///
+/// Uses the index if one is configured, see `index::load_fresh`.
+pub(crate) fn scan(config: Config, tx: Sender<PathBuf>) -> Result<()> {
+    if let Some(paths) = load_fresh(&config) {
+        for path in paths {
+            if tx.send(path).is_err() {
+                break;
+            }
+        }
+        return Ok(());
+    }
+    walk(config, tx)
+}
 
-    // Setup queue of directories to scan
-    let mut directories: VecDeque<String> = VecDeque::new();
-    directories.push_back(String::from(config.scan_root.as_str()));
-    let arc_directories = Arc::new(Mutex::new(directories));
+/// Like `scan` but always walks the file system
+pub(crate) fn walk(config: Config, tx: Sender<PathBuf>) -> Result<()> {
+    check_roots(&config)?;
+    let (arc_directories, workers) = ScanQueue::new(&config, root_paths(&config), None);
+    walk_queue(config, arc_directories, workers, tx);
+    Ok(())
+}
 
-    // List of join handles
-    let mut handles = vec![];
-    // List of receivers for messages/results from threads
-    let mut receivers = vec![];
+/// Walk only the directories below dir, which must be inside a scan root
+pub(crate) fn walk_below(config: Config, dir: &Path, tx: Sender<PathBuf>) {
+    let (arc_directories, workers) = ScanQueue::new(&config, Some(dir), None);
+    walk_queue(config, arc_directories, workers, tx);
+}
 
-    for _ in 0..config.num_threads {
-        // Clone for each thread
+fn walk_queue(
+    config: Config,
+    arc_directories: Queue,
+    workers: Vec<Worker<Pending>>,
+    tx: Sender<PathBuf>,
+) {
+    let mut handles = vec![];
+    for local in workers {
         let arc_dirs = Arc::clone(&arc_directories);
-        let pattern = pattern.clone();
         let config = config.clone();
-        let cache = cache.clone();
-
-        // Setup communication back to main thread
-        let (tx, rs) = channel();
-        receivers.push(rs);
+        let tx = tx.clone();
 
-        let handle = thread::spawn(move || match_worker(config, pattern, cache, arc_dirs, tx));
+        let handle = thread::spawn(move || {
+            let queue = Arc::clone(&arc_dirs);
+            scan_worker(&config, arc_dirs, local, |path, _root, _depth| {
+                // The receiver hanging up means nobody cares about more results, stop walking
+                if tx.send(path.to_path_buf()).is_err() {
+                    queue.stop();
+                }
+            })
+        });
         handles.push(handle);
     }
 
     for handle in handles {
         handle.join().unwrap();
     }
+}
 
-    let mut best_score = 0;
-    let mut best_result = String::from("");
-    for rs in receivers {
-        let (best_s, best_res) = rs.recv().unwrap();
-        if best_s > best_score {
-            best_score = best_s;
-            best_result = best_res;
+/// Match pattern against already known paths instead of scanning
+pub(crate) fn match_paths<I, S>(
+    config: &Config,
+    paths: I,
+    pattern: &str,
+    limit: usize,
+    cache: &LinkedHashMap<PathBuf, i64>,
+) -> Vec<MatchResult>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<Path>,
+{
+    let skim_matcher = SkimMatcherV2::default();
+    let mut results: Vec<MatchResult> = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        let weight = config.weight(path);
+        if let Some(result) = score_path(&skim_matcher, pattern, cache, path, weight) {
+            insert_ranked(&mut results, result, limit);
         }
     }
+    results.retain(|r| r.total() >= MIN_SCORE);
+    results
+}
+
+/// Stops a `CandidateStream`, can be used from another thread than the one reading it
+#[derive(Clone)]
+pub struct Cancel(Queue);
 
-    if best_score < 10 {
-        best_result = String::from(".");
+impl Cancel {
+    /// Make the scanning threads finish, candidates they already found can still be read
+    pub fn cancel(&self) {
+        self.0.stop();
     }
+}
 
-    best_result.replace(' ', "\\ ")
+/// Directories matching a pattern, scored as the scanning threads find them.
+///
+/// Candidates come in the order they are found, not ranked. Dropping the stream stops the scan.
+pub struct CandidateStream {
+    cancel: Cancel,
+    candidates: Receiver<Candidate>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl CandidateStream {
+    /// Stop the scan, the stream ends after the candidates already found
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Handle stopping the scan from another thread
+    pub fn canceller(&self) -> Cancel {
+        self.cancel.clone()
+    }
+}
+
+impl Iterator for CandidateStream {
+    type Item = Candidate;
+
+    fn next(&mut self) -> Option<Candidate> {
+        self.candidates.recv().ok()
+    }
+}
+
+impl Drop for CandidateStream {
+    fn drop(&mut self) {
+        self.cancel();
+        // Threads may be waiting for room in the channel
+        while self.candidates.recv().is_ok() {}
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Scan the scan roots in the background, scoring each directory against pattern.
+///
+/// The scan stops by itself when `scan_budget` from the config runs out.
+pub fn stream(config: Config, pattern: String) -> Result<CandidateStream> {
+    check_roots(&config)?;
+    let cache = get_visit_bonuses(config.clone())?;
+    let deadline = config.scan_budget.map(|budget| Instant::now() + budget);
+    let (arc_directories, workers) = ScanQueue::new(&config, root_paths(&config), deadline);
+    Ok(stream_queue(
+        config,
+        pattern,
+        cache,
+        arc_directories,
+        workers,
+    ))
+}
+
+fn stream_queue(
+    config: Config,
+    pattern: String,
+    cache: LinkedHashMap<PathBuf, i64>,
+    arc_directories: Queue,
+    workers: Vec<Worker<Pending>>,
+) -> CandidateStream {
+    let cache = Arc::new(cache);
+    let (tx, rs) = sync_channel(STREAM_BUFFER);
+    let mut handles = vec![];
+    for local in workers {
+        let arc_dirs = Arc::clone(&arc_directories);
+        let pattern = pattern.clone();
+        let config = config.clone();
+        let cache = Arc::clone(&cache);
+        let tx = tx.clone();
+
+        let handle =
+            thread::spawn(move || stream_worker(config, pattern, cache, arc_dirs, local, tx));
+        handles.push(handle);
+    }
+
+    CandidateStream {
+        cancel: Cancel(arc_directories),
+        candidates: rs,
+        handles,
+    }
+}
+
+fn match_scan(config: Config, pattern: String, limit: usize) -> Result<Vec<MatchResult>> {
+    // Listing several results is for comparing them, so only stop early when jumping
+    let early_exit = config.early_exit && limit == 1;
+    let candidates = stream(config, pattern.clone())?;
+    let cancel = candidates.canceller();
+    Ok(rank_candidates(
+        candidates, &cancel, &pattern, limit, early_exit,
+    ))
+}
+
+/// Keep the best limit candidates, cancelling the scan at the first strong match when early_exit
+fn rank_candidates<I: Iterator<Item = Candidate>>(
+    candidates: I,
+    cancel: &Cancel,
+    pattern: &str,
+    limit: usize,
+    early_exit: bool,
+) -> Vec<MatchResult> {
+    let mut results: Vec<MatchResult> = Vec::new();
+    for candidate in candidates {
+        if early_exit && is_strong_match(pattern, &candidate.result) {
+            cancel.cancel();
+        }
+        insert_ranked(&mut results, candidate.result, limit);
+    }
+    results
+}
+
+/// Find the best matching directories for pattern, at most limit of them, best first
+///
+/// Asks the daemon if one is running, otherwise matches against the index when one is configured
+/// and as a last resort scans the file system.
+pub fn matcher_list(config: Config, pattern: String, limit: usize) -> Result<Vec<MatchResult>> {
+    if let Some(results) = daemon::query(&config, &pattern, limit) {
+        return Ok(results);
+    }
+
+    if let Some(paths) = load_fresh(&config) {
+        let cache: LinkedHashMap<PathBuf, i64> = get_visit_bonuses(config.clone())?;
+        return Ok(match_paths(&config, paths, &pattern, limit, &cache));
+    }
+
+    let mut results = match_scan(config, pattern, limit)?;
+    results.retain(|r| r.total() >= MIN_SCORE);
+    Ok(results)
+}
+
+/// Escape result so it can be passed directly to `cd`, keeping the bytes of the path as they are
+pub fn escape(path: &Path) -> OsString {
+    let mut escaped = Vec::with_capacity(path.as_os_str().len());
+    for &byte in path.as_os_str().as_bytes() {
+        if byte == b' ' {
+            escaped.push(b'\\');
+        }
+        escaped.push(byte);
+    }
+    OsString::from_vec(escaped)
+}
+
+/// Quote path for a POSIX shell, keeping the bytes of the path as they are
+///
+/// Paths of only safe characters are left alone, others are put in single quotes
+/// with each `'` written as `'\''`, which survives spaces, globs, `$` and newlines.
+/// Bytes outside ASCII are never special to the shell, so names like `café` stay as they are.
+pub fn shell_quote(path: &Path) -> OsString {
+    let bytes = path.as_os_str().as_bytes();
+    let safe = |byte: &u8| {
+        byte.is_ascii_alphanumeric() || !byte.is_ascii() || b"_-./,:@%+=".contains(byte)
+    };
+    if !bytes.is_empty() && bytes.iter().all(safe) && bytes[0] != b'=' {
+        return path.as_os_str().to_owned();
+    }
+    let mut escaped = Vec::with_capacity(bytes.len() + 2);
+    escaped.push(b'\'');
+    for &byte in bytes {
+        if byte == b'\'' {
+            escaped.extend_from_slice(b"'\\''");
+        } else {
+            escaped.push(byte);
+        }
+    }
+    escaped.push(b'\'');
+    OsString::from_vec(escaped)
+}
+
+/// Best matching directory, unescaped, or `.` if nothing matched well enough
+pub fn best_match(config: Config, pattern: String) -> Result<PathBuf> {
+    match matcher_list(config, pattern, 1)?.pop() {
+        Some(result) => Ok(result.path),
+        None => Ok(PathBuf::from(".")),
+    }
+}
+
+/// Best matching directory escaped for `cd`, or `.` if nothing matched well enough
+pub fn matcher(config: Config, pattern: String) -> Result<OsString> {
+    Ok(escape(&best_match(config, pattern)?))
 }
 
 #[cfg(test)]
@@ -152,7 +837,7 @@ fn create_test_folders(folders: Vec<String>) -> (Config, PathBuf) {
 
     fs::remove_dir_all(dir.clone()).unwrap_or(());
 
-    config.scan_root = String::from(dir.as_path().to_str().unwrap());
+    config.scan_roots = vec![ScanRoot::new(dir.as_path().to_str().unwrap())];
 
     for folder in folders {
         let mut d = dir.clone();
@@ -168,27 +853,34 @@ fn create_test_folders(folders: Vec<String>) -> (Config, PathBuf) {
 mod tests {
     use super::*;
     use crate::cache::write_yaml;
+    use crate::ignores::GlobIgnoresBuilder;
+    use std::os::unix::fs::symlink;
+    use std::time::Duration;
 
     macro_rules! vec_string {
         ($($x:expr),*) => (vec![$($x.to_string()),*]);
     }
 
+    fn into_string(path: PathBuf) -> String {
+        path.into_os_string().into_string().unwrap()
+    }
+
     #[test]
     fn test_basic_exact_match() {
         let (config, mut dir) = create_test_folders(vec_string!["test"]);
 
-        let result: String = matcher(config, String::from("test"));
+        let result = matcher(config, String::from("test")).unwrap();
         dir.push("test");
-        assert_eq!(result, String::from(dir.as_path().to_str().unwrap()));
+        assert_eq!(result, dir.as_os_str());
     }
 
     #[test]
     fn test_prefer_later_in_string() {
         let lines: Vec<String> = vec_string!["projects", "projects/project", "projects/hello"];
         let (config, mut dir) = create_test_folders(lines);
-        let result: String = matcher(config, String::from("psp"));
+        let result = matcher(config, String::from("psp")).unwrap();
         dir.push("projects/project");
-        assert_eq!(result, String::from(dir.as_path().to_str().unwrap()));
+        assert_eq!(result, dir.as_os_str());
     }
 
     #[test]
@@ -205,9 +897,9 @@ mod tests {
             format!("---\n{}/projects/c: 5", dir.as_path().to_str().unwrap()).as_bytes(),
         );
 
-        let result: String = matcher(config, String::from("proj"));
+        let result = matcher(config, String::from("proj")).unwrap();
         dir.push("projects/c");
-        assert_eq!(result, String::from(dir.as_path().to_str().unwrap()));
+        assert_eq!(result, dir.as_os_str());
     }
 
     #[test]
@@ -217,26 +909,545 @@ mod tests {
         let (config, mut dir) = create_test_folders(lines);
         dir.push("projects/project other");
 
-        let result: String = matcher(config, String::from("other"));
-        assert!(result.as_str().ends_with("/projects/project\\ other"));
+        let result = matcher(config, String::from("other")).unwrap();
+        assert!(result.as_bytes().ends_with(b"/projects/project\\ other"));
+    }
+
+    #[test]
+    fn test_shell_quote_quotes_shell_characters() {
+        let escaped = |path: &str| shell_quote(Path::new(path)).into_string().unwrap();
+        assert_eq!(
+            escaped("/home/me/src/fast-jump_1.0"),
+            "/home/me/src/fast-jump_1.0"
+        );
+        assert_eq!(escaped("a b"), "'a b'");
+        assert_eq!(escaped("it's"), "'it'\\''s'");
+        assert_eq!(escaped("$HOME/*"), "'$HOME/*'");
+        assert_eq!(escaped("tab\tand\nnewline"), "'tab\tand\nnewline'");
+        assert_eq!(escaped("~user"), "'~user'");
+        assert_eq!(escaped("/src/café/日本"), "/src/café/日本");
+        assert_eq!(escaped(""), "''");
     }
 
     #[test]
     fn test_directory_does_not_exist() {
         let lines: Vec<String> = vec_string![];
         let (config, _dir) = create_test_folders(lines);
-        let cache: LinkedHashMap<String, i64> = get_current_state(config.clone());
-        let mut directories: VecDeque<String> = VecDeque::new();
-        directories.push_back(String::from("asdf"));
-        let arc_directories = Arc::new(Mutex::new(directories));
+        let cache: LinkedHashMap<PathBuf, i64> = get_visit_bonuses(config.clone()).unwrap();
+        let directories = vec![Pending {
+            path: PathBuf::from("asdf"),
+            root: 0,
+            depth: 0,
+            ignores: IgnoreStack::default(),
+            via_link: false,
+            dev: 0,
+        }];
+        let (arc_directories, workers) =
+            ScanQueue::with_pending(&config, Filesystems::default(), directories, None);
+
+        let candidates = stream_queue(
+            config,
+            String::from("projects"),
+            cache,
+            arc_directories,
+            workers,
+        );
+
+        assert_eq!(candidates.count(), 0);
+    }
+
+    #[test]
+    fn test_missing_scan_root_is_an_error() {
+        let mut config = test_config();
+        config.scan_roots = vec![ScanRoot::new("/nonexisting/fj_root")];
+
+        match matcher_list(config.clone(), String::from("projects"), 1) {
+            Err(Error::Traversal { path, .. }) => {
+                assert_eq!(path, Path::new("/nonexisting/fj_root"))
+            }
+            _ => panic!("Expected traversal error"),
+        }
+        let (tx, _rx) = channel();
+        assert_eq!(walk(config, tx).err().map(|e| e.exit_code()), Some(66));
+    }
+
+    #[test]
+    fn test_missing_scan_root_is_skipped_if_others_are_readable() {
+        let lines: Vec<String> = vec_string!["projects"];
+        let (mut config, dir) = create_test_folders(lines);
+        config
+            .scan_roots
+            .push(ScanRoot::new("/nonexisting/fj_root"));
+
+        let result = matcher_list(config, String::from("projects"), 1).unwrap();
+        assert_eq!(result[0].path, dir.join("projects"));
+    }
+
+    #[test]
+    fn test_uses_index_when_present() {
+        let lines: Vec<String> = vec_string!["projects/indexed"];
+        let (mut config, mut dir) = create_test_folders(lines);
+        let mut index = dir.clone();
+        index.push("index.txt");
+        config.index = Some(index);
+        crate::index::rebuild(&config).unwrap();
+
+        // Not in the fresh index so not found until the index is refreshed
+        dir.push("projects/later");
+        fs::create_dir_all(dir.as_path()).unwrap();
+        let results = matcher_list(config.clone(), String::from("later"), 5).unwrap();
+        assert!(results.iter().all(|r| !r.path.ends_with("later")));
+
+        config.index_max_age = 0;
+        let result = matcher(config, String::from("later")).unwrap();
+        assert_eq!(result, dir.as_os_str());
+    }
+
+    #[test]
+    fn test_scan_sends_all_directories() {
+        let lines: Vec<String> = vec_string!["projects/a", "projects/b", "node_modules/c"];
+        let (mut config, dir) = create_test_folders(lines);
+        config.ignores.insert(String::from("node_modules"));
+
+        let (tx, rs) = channel();
+        scan(config, tx).unwrap();
+
+        let mut found: Vec<String> = rs.iter().map(into_string).collect();
+        found.sort();
+        let root = dir.as_path().to_str().unwrap();
+        let expected: Vec<String> = vec![
+            format!("{}/projects", root),
+            format!("{}/projects/a", root),
+            format!("{}/projects/b", root),
+        ];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_threads_scan_every_directory_once() {
+        let mut lines: Vec<String> = vec_string!["a/b/c/d/e/f/g"];
+        for i in 0..50 {
+            lines.push(format!("wide/{}/{}", i, i));
+        }
+        let (mut config, _dir) = create_test_folders(lines);
+        config.num_threads = 4;
+
+        let (tx, rs) = channel();
+        walk(config, tx).unwrap();
+
+        let found: Vec<String> = rs.iter().map(into_string).collect();
+        let unique: HashSet<&String> = found.iter().collect();
+        assert_eq!(found.len(), 7 + 1 + 50 * 2);
+        assert_eq!(unique.len(), found.len());
+    }
+
+    #[test]
+    fn test_unreadable_directory_does_not_stop_scan() {
+        let lines: Vec<String> = vec_string!["projects"];
+        let (config, dir) = create_test_folders(lines);
+        let directories = vec![
+            Pending {
+                path: PathBuf::from("asdf"),
+                root: 0,
+                depth: 0,
+                ignores: IgnoreStack::default(),
+                via_link: false,
+                dev: 0,
+            },
+            Pending {
+                path: dir.clone(),
+                root: 0,
+                depth: 0,
+                ignores: IgnoreStack::default(),
+                via_link: false,
+                dev: 0,
+            },
+        ];
+        let (queue, workers) =
+            ScanQueue::with_pending(&config, Filesystems::default(), directories, None);
+
+        let (tx, rs) = channel();
+        walk_queue(config, queue, workers, tx);
+        assert_eq!(rs.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_walk_stops_when_receiver_hangs_up() {
+        let lines: Vec<String> = vec_string!["a/b/c", "d/e/f"];
+        let (config, dir) = create_test_folders(lines);
+        let (queue, workers) = ScanQueue::new(&config, Some(dir.as_path()), None);
+
+        let (tx, rs) = channel();
+        drop(rs);
+        walk_queue(config, Arc::clone(&queue), workers, tx);
+        assert!(queue.stopped());
+        assert!(!queue.finished());
+    }
+
+    #[test]
+    fn test_scan_multiple_roots() {
+        let lines: Vec<String> = vec_string![
+            "home/notes",
+            "home/src/app/vendor",
+            "home/src/app/src",
+            "data/sets/deep/deeper"
+        ];
+        let (mut config, dir) = create_test_folders(lines);
+        let root = dir.as_path().to_str().unwrap();
+        let mut src = ScanRoot::new(&format!("{}/home/src", root));
+        src.ignores.insert(String::from("vendor"));
+        let mut data = ScanRoot::new(&format!("{}/data", root));
+        data.max_depth = Some(2);
+        config.scan_roots = vec![ScanRoot::new(&format!("{}/home", root)), src, data];
+        config.num_threads = 3;
+
+        let (tx, rs) = channel();
+        scan(config, tx).unwrap();
+
+        let mut found: Vec<String> = rs.iter().map(into_string).collect();
+        found.sort();
+        let expected: Vec<String> = vec![
+            format!("{}/data/sets", root),
+            format!("{}/data/sets/deep", root),
+            format!("{}/home/notes", root),
+            format!("{}/home/src", root),
+            format!("{}/home/src/app", root),
+            format!("{}/home/src/app/src", root),
+        ];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_symlinks() {
+        let lines: Vec<String> = vec_string!["root/real/inner", "ext1/x", "ext2/y"];
+        let (mut config, dir) = create_test_folders(lines);
+        let base = dir.as_path().to_str().unwrap();
+        let root = format!("{}/root", base);
+        symlink(format!("{}/ext1", base), format!("{}/link", root)).unwrap();
+        symlink(format!("{}/ext2", base), format!("{}/ext1/to_ext2", base)).unwrap();
+        symlink(format!("{}/ext1", base), format!("{}/ext2/back", base)).unwrap();
+        symlink(&root, format!("{}/real/loop", root)).unwrap();
+        // Parents of the root would bring the whole root back below the link
+        symlink(base, format!("{}/up", root)).unwrap();
+        symlink(format!("{}/real", root), format!("{}/real2", root)).unwrap();
+        config.scan_roots = vec![ScanRoot::new(&root)];
+
+        let scan_relative = |symlinks: Symlinks| {
+            let mut config = config.clone();
+            config.symlinks = symlinks;
+            let (tx, rs) = channel();
+            walk(config, tx).unwrap();
+            let mut found: Vec<String> = rs
+                .iter()
+                .map(|p| into_string(p)[root.len() + 1..].to_string())
+                .collect();
+            found.sort();
+            found
+        };
+
+        assert_eq!(scan_relative(Symlinks::Never), vec!["real", "real/inner"]);
+        assert_eq!(
+            scan_relative(Symlinks::FollowOnce),
+            vec!["link", "link/x", "real", "real/inner"]
+        );
+        assert_eq!(
+            scan_relative(Symlinks::Follow),
+            vec![
+                "link",
+                "link/to_ext2",
+                "link/to_ext2/y",
+                "link/x",
+                "real",
+                "real/inner"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let lines: Vec<String> = vec_string!["a/b/c/d", "e/f/g"];
+        let (mut config, dir) = create_test_folders(lines);
+        let root = dir.as_path().to_str().unwrap();
+        config.max_depth = Some(2);
+
+        let scan_all = |config: Config| {
+            let (tx, rs) = channel();
+            scan(config, tx).unwrap();
+            let mut found: Vec<String> = rs.iter().map(into_string).collect();
+            found.sort();
+            found
+        };
+        let expected: Vec<String> = vec![
+            format!("{}/a", root),
+            format!("{}/a/b", root),
+            format!("{}/e", root),
+            format!("{}/e/f", root),
+        ];
+        assert_eq!(scan_all(config.clone()), expected);
+
+        // Depth is counted from the root the directory is in, and roots can have their own limit
+        let mut e = ScanRoot::new(&format!("{}/e", root));
+        e.max_depth = Some(1);
+        config.scan_roots.push(e);
+        config
+            .scan_roots
+            .push(ScanRoot::new(&format!("{}/a/b", root)));
+        let expected: Vec<String> = vec![
+            format!("{}/a", root),
+            format!("{}/a/b", root),
+            format!("{}/a/b/c", root),
+            format!("{}/a/b/c/d", root),
+            format!("{}/e", root),
+            format!("{}/e/f", root),
+        ];
+        assert_eq!(scan_all(config.clone()), expected);
+
+        let (tx, rs) = channel();
+        walk_below(config, &dir.join("e/f"), tx);
+        assert_eq!(rs.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_early_exit() {
+        let mut lines: Vec<String> = vec_string!["o"];
+        for i in 0..200 {
+            lines.push(format!("other/{}", i));
+        }
+        let (mut config, dir) = create_test_folders(lines);
+        let root = dir.as_path().to_str().unwrap();
+        let mut previous_visits = dir.clone();
+        previous_visits.push("visits.yml");
+        config.previous_visits = Some(previous_visits.clone());
+        write_yaml(previous_visits, format!("---\n{}/o: 50", root).as_bytes());
+        config.early_exit = true;
+
+        let results = matcher_list(config.clone(), String::from("o"), 1).unwrap();
+        assert_eq!(results[0].path, format!("{}/o", root));
+
+        // Listing keeps scanning
+        let results = matcher_list(config.clone(), String::from("o"), 2).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].path, format!("{}/o", root));
+
+        // The walk stops at the strong match, only what was already sent or being sent is still read
+        let threads = config.num_threads as usize;
+        let count_ranked = |early_exit: bool| {
+            let candidates = stream(config.clone(), String::from("o")).unwrap();
+            let cancel = candidates.canceller();
+            let mut count = 0;
+            let counted = candidates.inspect(|_| count += 1);
+            let results = rank_candidates(counted, &cancel, "o", 1, early_exit);
+            assert_eq!(results[0].path, format!("{}/o", root));
+            count
+        };
+        assert_eq!(count_ranked(false), 202);
+        assert!(count_ranked(true) <= STREAM_BUFFER + threads + 1);
+    }
+
+    #[test]
+    fn test_scan_budget() {
+        let lines: Vec<String> = vec_string!["projects/a"];
+        let (mut config, _dir) = create_test_folders(lines);
+        config.scan_budget = Some(Duration::ZERO);
+        assert_eq!(stream(config, String::from("a")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_stream_candidates() {
+        let lines: Vec<String> = vec_string!["a/b"];
+        let (config, dir) = create_test_folders(lines);
+        let root = dir.as_path().to_str().unwrap();
+
+        let candidates: Vec<Candidate> = stream(config, String::from("b")).unwrap().collect();
+        let b = candidates
+            .iter()
+            .find(|c| c.result.path == format!("{}/a/b", root))
+            .unwrap();
+        assert_eq!(b.depth, 2);
+        assert!(b.result.score > 0);
+    }
+
+    #[test]
+    fn test_stream_cancel() {
+        let mut lines: Vec<String> = vec![];
+        for i in 0..200 {
+            lines.push(format!("other/{}", i));
+        }
+        let (config, _dir) = create_test_folders(lines);
+
+        let mut candidates = stream(config, String::from("o")).unwrap();
+        assert!(candidates.next().is_some());
+        candidates.cancel();
+        // Threads stop after the candidates waiting in the channel and the one being sent
+        assert!(candidates.count() <= STREAM_BUFFER + 1);
+    }
+
+    #[test]
+    fn test_skip_mounts() {
+        let lines: Vec<String> = vec_string!["a/b", "e/f"];
+        let (mut config, dir) = create_test_folders(lines);
+        let root = dir.as_path().to_str().unwrap();
+        config.skip_mounts = vec![dir.join("a")];
 
         let (tx, rs) = channel();
-        match_worker(config, String::from("projects"), cache, arc_directories, tx);
+        scan(config.clone(), tx).unwrap();
+        let mut found: Vec<String> = rs.iter().map(into_string).collect();
+        found.sort();
+        assert_eq!(found, vec![format!("{}/e", root), format!("{}/e/f", root)]);
 
-        let (best_s, best_res) = rs.recv().unwrap();
+        let (tx, rs) = channel();
+        walk_below(config, &dir.join("a"), tx);
+        assert_eq!(rs.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_root_weight() {
+        let lines: Vec<String> = vec_string!["work/project", "play/project"];
+        let (mut config, dir) = create_test_folders(lines);
+        let root = dir.as_path().to_str().unwrap();
+        let mut play = ScanRoot::new(&format!("{}/play", root));
+        play.weight = 2.0;
+        config.scan_roots = vec![ScanRoot::new(&format!("{}/work", root)), play];
+
+        let results = matcher_list(config.clone(), String::from("project"), 2).unwrap();
+        assert_eq!(results[0].path, format!("{}/play/project", root));
+        assert_eq!(results[0].score, 2 * results[1].score);
+
+        let results = match_paths(
+            &config,
+            vec![&results[1].path],
+            "project",
+            1,
+            &LinkedHashMap::new(),
+        );
+        assert_eq!(results[0].path, format!("{}/work/project", root));
+    }
+
+    #[test]
+    fn test_scan_hidden() {
+        let lines: Vec<String> = vec_string![".config/nvim", ".cache/big", "projects/.git/objects"];
+        let (mut config, dir) = create_test_folders(lines);
+        config.hidden_allow.insert(String::from(".config"));
+
+        let scan_all = |config: Config| {
+            let (tx, rs) = channel();
+            scan(config, tx).unwrap();
+            let mut found: Vec<String> = rs.iter().map(into_string).collect();
+            found.sort();
+            found
+        };
+        let root = dir.as_path().to_str().unwrap();
+        assert_eq!(
+            scan_all(config.clone()),
+            vec![
+                format!("{}/.config", root),
+                format!("{}/.config/nvim", root),
+                format!("{}/projects", root),
+            ]
+        );
+
+        config.scan_hidden = true;
+        assert_eq!(scan_all(config.clone()).len(), 7);
 
-        assert_eq!(best_s, 0);
-        assert_eq!(best_res, String::from(""));
+        // Only folders below scan_root are checked
+        config.scan_hidden = false;
+        config.scan_roots = vec![ScanRoot::new(&format!("{}/.cache", root))];
+        assert_eq!(scan_all(config), vec![format!("{}/.cache/big", root)]);
+    }
+
+    #[test]
+    fn test_scan_skips_glob_ignores() {
+        let lines: Vec<String> = vec_string![
+            "work/a/vendor",
+            "work/a/src",
+            "lib/pkg.egg-info",
+            "lib/vendor"
+        ];
+        let (mut config, dir) = create_test_folders(lines);
+        let mut globs = GlobIgnoresBuilder::default();
+        globs.add("work/*/vendor", "").unwrap();
+        globs.add("*.egg-info", "").unwrap();
+        config.ignore_globs = globs.build();
+
+        let (tx, rs) = channel();
+        scan(config, tx).unwrap();
+
+        let mut found: Vec<String> = rs.iter().map(into_string).collect();
+        found.sort();
+        let root = dir.as_path().to_str().unwrap();
+        let expected: Vec<String> = vec![
+            format!("{}/lib", root),
+            format!("{}/lib/vendor", root),
+            format!("{}/work", root),
+            format!("{}/work/a", root),
+            format!("{}/work/a/src", root),
+        ];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_scan_honors_ignore_files() {
+        let lines: Vec<String> = vec_string![
+            "projects/build/out",
+            "projects/build-tools",
+            "projects/a/build",
+            "projects/a/vendor",
+            "vendor"
+        ];
+        let (config, dir) = create_test_folders(lines);
+        fs::write(dir.join(".fjignore"), "/vendor\n").unwrap();
+        fs::write(dir.join("projects/.fjignore"), "build*\n!build-tools\n").unwrap();
+
+        let (tx, rs) = channel();
+        scan(config, tx).unwrap();
+
+        let mut found: Vec<String> = rs.iter().map(into_string).collect();
+        found.sort();
+        let root = dir.as_path().to_str().unwrap();
+        let expected: Vec<String> = vec![
+            format!("{}/projects", root),
+            format!("{}/projects/a", root),
+            format!("{}/projects/a/vendor", root),
+            format!("{}/projects/build-tools", root),
+        ];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn test_list_ranked_results() {
+        let lines: Vec<String> = vec_string!["projects/alpha", "projects/beta", "other"];
+        let (mut config, dir) = create_test_folders(lines);
+        let mut previous_visits = dir.clone();
+        previous_visits.push("visits.yml");
+        config.previous_visits = Some(previous_visits.clone());
+
+        let root = dir.as_path().to_str().unwrap();
+        write_yaml(
+            previous_visits,
+            format!("---\n{}/projects/beta: 50", root).as_bytes(),
+        );
+
+        let results = matcher_list(config, String::from("projects"), 2).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].path, format!("{}/projects/beta", root));
+        assert_eq!(results[0].bonus, 50);
+        assert!(results[0].total() >= results[1].total());
+        assert_eq!(results[1].bonus, 0);
+    }
+
+    #[test]
+    fn test_insert_ranked_keeps_limit() {
+        let mut results = vec![];
+        for (path, score) in [("a", 10), ("b", 30), ("c", 20), ("d", 30)] {
+            let result = MatchResult {
+                path: PathBuf::from(path),
+                score,
+                bonus: 0,
+            };
+            insert_ranked(&mut results, result, 3);
+        }
+        let paths: Vec<&str> = results.iter().map(|r| r.path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["b", "d", "c"]);
     }
 }
 
@@ -257,6 +1468,50 @@ mod benchs {
         lines
     }
 
+    /// Wide tree of 20 * 10 * 10 directories, so there is work to share between threads
+    fn generate_tree() -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        for a in 0..20 {
+            for b in 0..10 {
+                for c in 0..10 {
+                    lines.push(format!("{}/{}/{}", a, b, c));
+                }
+            }
+        }
+        lines
+    }
+
+    fn bench_walk(b: &mut Bencher, num_threads: u8) {
+        let (mut config, _dir) = create_test_folders(generate_tree());
+        config.num_threads = num_threads;
+
+        b.iter(|| {
+            let (tx, rs) = channel();
+            walk(config.clone(), tx).unwrap();
+            black_box(rs.iter().count());
+        });
+    }
+
+    #[bench]
+    fn bench_walk_one_thread(b: &mut Bencher) {
+        bench_walk(b, 1);
+    }
+
+    #[bench]
+    fn bench_walk_two_threads(b: &mut Bencher) {
+        bench_walk(b, 2);
+    }
+
+    #[bench]
+    fn bench_walk_four_threads(b: &mut Bencher) {
+        bench_walk(b, 4);
+    }
+
+    #[bench]
+    fn bench_walk_eight_threads(b: &mut Bencher) {
+        bench_walk(b, 8);
+    }
+
     #[bench]
     fn bench_scan_random_strings_single_thread(b: &mut Bencher) {
         let lines = generate_lines();
@@ -264,7 +1519,7 @@ mod benchs {
         config.num_threads = 1;
 
         b.iter(|| {
-            black_box(matcher(config.clone(), get_rand_string(20)));
+            black_box(matcher(config.clone(), get_rand_string(20)).unwrap());
         });
     }
 
@@ -275,7 +1530,7 @@ mod benchs {
         config.num_threads = 2;
 
         b.iter(|| {
-            black_box(matcher(config.clone(), get_rand_string(20)));
+            black_box(matcher(config.clone(), get_rand_string(20)).unwrap());
         });
     }
 
@@ -286,7 +1541,7 @@ mod benchs {
         config.num_threads = 5;
 
         b.iter(|| {
-            black_box(matcher(config.clone(), get_rand_string(20)));
+            black_box(matcher(config.clone(), get_rand_string(20)).unwrap());
         });
     }
 }
@@ -0,0 +1,345 @@
+use crate::config::{expand_home, Config, ScanRoot};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Name of the files with ignore rules for the directory they are in and everything below it
+pub const IGNORE_FILE: &str = ".fjignore";
+
+const GITIGNORE_FILE: &str = ".gitignore";
+
+/// Check if changing the file at path may change which directories are ignored
+pub fn is_ignore_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name == IGNORE_FILE || name == GITIGNORE_FILE)
+}
+
+/// Rules from the `.fjignore` files in a directory and its parents.
+///
+/// The rules use the same syntax as `.gitignore`, so patterns can be negated with `!`,
+/// anchored to the directory of the file with a leading `/` and restricted to directories
+/// with a trailing `/`. Rules in deeper directories take precedence.
+///
+/// With `respect_gitignore` configured the rules git uses inside repositories are included too:
+/// the global excludes file, `.git/info/exclude` and `.gitignore` files. `.fjignore` rules
+/// take precedence over git's rules for the same directory.
+#[derive(Clone, Default)]
+pub struct IgnoreStack {
+    top: Option<Arc<Level>>,
+    in_repo: bool,
+}
+
+struct Level {
+    rules: Gitignore,
+    parent: Option<Arc<Level>>,
+}
+
+impl IgnoreStack {
+    fn push(mut self, rules: Gitignore) -> IgnoreStack {
+        if !rules.is_empty() {
+            let parent = self.top.take();
+            self.top = Some(Arc::new(Level { rules, parent }));
+        }
+        self
+    }
+
+    /// Add rules from file, patterns are relative to root
+    fn push_file(self, root: &Path, file: &Path) -> IgnoreStack {
+        if !file.is_file() {
+            return self;
+        }
+        let mut builder = GitignoreBuilder::new(root);
+        if let Some(e) = builder.add(file) {
+            eprintln!("Error: Failed to read {}: {}", file.display(), e);
+        }
+        match builder.build() {
+            Ok(rules) => self.push(rules),
+            Err(e) => {
+                eprintln!("Error: Failed to read {}: {}", file.display(), e);
+                self
+            }
+        }
+    }
+
+    /// Rules for the entries of dir, given the rules that apply to dir itself
+    pub fn enter(&self, config: &Config, dir: &Path) -> IgnoreStack {
+        let mut stack = self.clone();
+        if config.respect_gitignore {
+            if dir.join(".git").exists() {
+                stack.in_repo = true;
+                let (global, error) = GitignoreBuilder::new(dir).build_global();
+                if let Some(e) = error {
+                    eprintln!("Error: Failed to read global git excludes: {}", e);
+                }
+                stack = stack
+                    .push(global)
+                    .push_file(dir, &dir.join(".git/info/exclude"));
+            }
+            if stack.in_repo {
+                stack = stack.push_file(dir, &dir.join(GITIGNORE_FILE));
+            }
+        }
+        stack.push_file(dir, &dir.join(IGNORE_FILE))
+    }
+
+    /// Rules for the entries of dir, reading every ignore file from root down to dir
+    pub fn load(config: &Config, root: &ScanRoot, dir: &Path) -> IgnoreStack {
+        let root = Path::new(&root.path);
+        let mut stack = IgnoreStack::default().enter(config, root);
+        if let Ok(relative) = dir.strip_prefix(root) {
+            let mut current = root.to_path_buf();
+            for component in relative.components() {
+                current.push(component);
+                stack = stack.enter(config, &current);
+            }
+        }
+        stack
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut current = &self.top;
+        while let Some(level) = current {
+            match level.rules.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => current = &level.parent,
+            }
+        }
+        false
+    }
+}
+
+/// Check if an ignore from the config is a pattern rather than a plain folder name
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(|c| "*?[]{}/".contains(c)) || pattern.starts_with('~')
+}
+
+/// Glob patterns from `ignores` in the config.
+///
+/// Patterns without `/` match folder names anywhere, like `*.egg-info`. Patterns starting with
+/// `/` or `~/` match full paths, like `~/Library/*`. Other patterns match paths relative to
+/// the scan root, like `work/*/vendor` or `**/target`. `*` never matches `/`, use `**` for that.
+#[derive(Clone, Default)]
+pub struct GlobIgnores {
+    names: GlobSet,
+    absolute: GlobSet,
+    relative: GlobSet,
+    /// Patterns as added, with `~` expanded
+    patterns: Vec<String>,
+}
+
+pub struct GlobIgnoresBuilder {
+    names: GlobSetBuilder,
+    absolute: GlobSetBuilder,
+    relative: GlobSetBuilder,
+    patterns: Vec<String>,
+}
+
+impl Default for GlobIgnoresBuilder {
+    fn default() -> GlobIgnoresBuilder {
+        GlobIgnoresBuilder {
+            names: GlobSetBuilder::new(),
+            absolute: GlobSetBuilder::new(),
+            relative: GlobSetBuilder::new(),
+            patterns: vec![],
+        }
+    }
+}
+
+impl GlobIgnoresBuilder {
+    /// Add pattern, home is used in place of a leading `~`
+    pub fn add(&mut self, pattern: &str, home: &str) -> Result<(), String> {
+        let expanded = expand_home(pattern, home);
+        let glob = GlobBuilder::new(&expanded)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| e.kind().to_string())?;
+
+        if !expanded.contains('/') {
+            self.names.add(glob);
+        } else if expanded.starts_with('/') {
+            self.absolute.add(glob);
+        } else {
+            self.relative.add(glob);
+        }
+        self.patterns.push(expanded);
+        Ok(())
+    }
+
+    pub fn build(self) -> GlobIgnores {
+        let build = |builder: GlobSetBuilder| builder.build().unwrap_or_default();
+        GlobIgnores {
+            names: build(self.names),
+            absolute: build(self.absolute),
+            relative: build(self.relative),
+            patterns: self.patterns,
+        }
+    }
+}
+
+impl GlobIgnores {
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub fn is_match(&self, scan_root: &str, path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| self.names.is_match(name))
+            || self.absolute.is_match(path)
+            || path
+                .strip_prefix(scan_root)
+                .is_ok_and(|relative| self.relative.is_match(relative))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn setup(name: &str, files: Vec<(&str, &str)>) -> (Config, PathBuf) {
+        let mut config: Config = test_config();
+        let mut dir = env::temp_dir();
+        dir.push(format!("{}_fj_ignores_tests", name));
+        fs::remove_dir_all(dir.clone()).unwrap_or(());
+        fs::create_dir_all(dir.clone()).unwrap();
+        for (path, contents) in files {
+            let file = dir.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, contents).unwrap();
+        }
+        config.scan_roots = vec![ScanRoot::new(dir.to_str().unwrap())];
+        (config, dir)
+    }
+
+    #[test]
+    fn test_no_rules() {
+        let stack = IgnoreStack::default();
+        assert!(!stack.is_ignored(Path::new("/some/dir"), true));
+    }
+
+    #[test]
+    fn test_gitignore_semantics() {
+        let (config, dir) = setup(
+            "semantics",
+            vec![(".fjignore", "build*\n!build-tools\n/top\nonly-dirs/\n")],
+        );
+        let stack = IgnoreStack::load(&config, &config.scan_roots[0], &dir);
+
+        assert!(stack.is_ignored(&dir.join("build-output"), true));
+        assert!(!stack.is_ignored(&dir.join("build-tools"), true));
+        assert!(stack.is_ignored(&dir.join("top"), true));
+        assert!(!stack.is_ignored(&dir.join("a/top"), true));
+        assert!(stack.is_ignored(&dir.join("a/only-dirs"), true));
+        assert!(!stack.is_ignored(&dir.join("a/only-dirs"), false));
+    }
+
+    #[test]
+    fn test_deeper_rules_take_precedence() {
+        let (config, dir) = setup(
+            "precedence",
+            vec![
+                (".fjignore", "vendor\nkeep\n"),
+                ("project/.fjignore", "!vendor\n"),
+            ],
+        );
+
+        let top = IgnoreStack::load(&config, &config.scan_roots[0], &dir);
+        assert!(top.is_ignored(&dir.join("vendor"), true));
+
+        let project = IgnoreStack::load(&config, &config.scan_roots[0], &dir.join("project"));
+        assert!(!project.is_ignored(&dir.join("project/vendor"), true));
+        assert!(project.is_ignored(&dir.join("project/keep"), true));
+
+        // Rules in project do not leak to its siblings
+        let other = top.enter(&config, &dir.join("other"));
+        assert!(other.is_ignored(&dir.join("other/vendor"), true));
+    }
+
+    #[test]
+    fn test_respect_gitignore() {
+        let (mut config, dir) = setup(
+            "gitignore",
+            vec![
+                (".gitignore", "outside\n"),
+                ("repo/.git/info/exclude", "excluded\n"),
+                ("repo/.gitignore", "target\n"),
+                ("repo/.fjignore", "!kept\n"),
+                ("repo/sub/.gitignore", "kept\n"),
+            ],
+        );
+        let repo = dir.join("repo");
+
+        let stack = IgnoreStack::load(&config, &config.scan_roots[0], &repo);
+        assert!(!stack.is_ignored(&repo.join("target"), true));
+
+        config.respect_gitignore = true;
+        let stack = IgnoreStack::load(&config, &config.scan_roots[0], &repo);
+        assert!(stack.is_ignored(&repo.join("target"), true));
+        assert!(stack.is_ignored(&repo.join("excluded"), true));
+        // .gitignore files only count inside repositories
+        assert!(!IgnoreStack::load(&config, &config.scan_roots[0], &dir)
+            .is_ignored(&dir.join("outside"), true));
+
+        let sub = IgnoreStack::load(&config, &config.scan_roots[0], &repo.join("sub"));
+        assert!(sub.is_ignored(&repo.join("sub/kept"), true));
+        assert!(sub.is_ignored(&repo.join("sub/target"), true));
+    }
+
+    fn glob_ignores(patterns: Vec<&str>) -> GlobIgnores {
+        let mut builder = GlobIgnoresBuilder::default();
+        for pattern in patterns {
+            builder.add(pattern, "/home/me").unwrap();
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_is_glob() {
+        assert!(!is_glob("node_modules"));
+        assert!(is_glob("*.egg-info"));
+        assert!(is_glob("work/vendor"));
+        assert!(is_glob("~/Library"));
+    }
+
+    #[test]
+    fn test_glob_ignores() {
+        let globs = glob_ignores(vec![
+            "*.egg-info",
+            "~/Library/*",
+            "work/*/vendor",
+            "**/target",
+        ]);
+        let root = "/home/me";
+        let matches = |path: &str| globs.is_match(root, Path::new(path));
+
+        assert!(matches("/home/me/src/fastjump.egg-info"));
+        assert!(matches("/home/me/Library/Caches"));
+        assert!(!matches("/home/me/Library"));
+        assert!(!matches("/home/me/Library/Caches/deeper"));
+        assert!(matches("/home/me/work/project/vendor"));
+        assert!(!matches("/home/me/work/a/b/vendor"));
+        assert!(!matches("/home/me/other/work/project/vendor"));
+        assert!(matches("/home/me/target"));
+        assert!(matches("/home/me/a/b/target"));
+        assert!(!matches("/home/me/targets"));
+    }
+
+    #[test]
+    fn test_invalid_glob() {
+        let mut builder = GlobIgnoresBuilder::default();
+        assert!(builder.add("work/[vendor", "/home/me").is_err());
+    }
+
+    #[test]
+    fn test_is_ignore_file() {
+        assert!(is_ignore_file(Path::new("/a/.fjignore")));
+        assert!(is_ignore_file(Path::new("/a/.gitignore")));
+        assert!(!is_ignore_file(Path::new("/a/b")));
+    }
+}
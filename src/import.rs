@@ -0,0 +1,336 @@
+use crate::cache::{now, update_state, Visit};
+use crate::config::Config;
+use crate::error::{self, Error};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Count given to the most used imported directory when there are no previous visits to compare with
+const DEFAULT_MAX_COUNT: i64 = 100;
+
+/// Other directory jumpers whose history can be imported
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    Autojump,
+    Z,
+    Fasd,
+    Zoxide,
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Source, String> {
+        match s {
+            "autojump" => Ok(Source::Autojump),
+            "z" => Ok(Source::Z),
+            "fasd" => Ok(Source::Fasd),
+            "zoxide" => Ok(Source::Zoxide),
+            _ => Err(format!(
+                "Unknown source {}, expected autojump, z, fasd or zoxide",
+                s
+            )),
+        }
+    }
+}
+
+/// Directory with its weight in the source database
+#[derive(Debug, PartialEq)]
+struct Entry {
+    path: PathBuf,
+    weight: f64,
+    last_visit: Option<u64>,
+}
+
+/// Non-empty lines of contents, paths in them need not be UTF-8
+fn lines(contents: &[u8]) -> impl Iterator<Item = &[u8]> {
+    contents
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+}
+
+fn path_from(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsString::from_vec(bytes.to_vec()))
+}
+
+fn number<T: FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// autojump.txt has one `<weight>\t<path>` per line
+fn parse_autojump(contents: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut entries = vec![];
+    for line in lines(contents) {
+        let shown = String::from_utf8_lossy(line);
+        let mut parts = line.splitn(2, |&b| b == b'\t');
+        let (weight, path) = match (parts.next(), parts.next()) {
+            (Some(weight), Some(path)) => (weight, path),
+            _ => return Err(format!("Expected <weight>\\t<path>, got {}", shown)),
+        };
+        entries.push(Entry {
+            path: path_from(path),
+            weight: number(weight).ok_or_else(|| format!("Bad weight in {}", shown))?,
+            last_visit: None,
+        });
+    }
+    Ok(entries)
+}
+
+/// z and fasd both have one `<path>|<rank>|<time>` per line
+fn parse_z(contents: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut entries = vec![];
+    for line in lines(contents) {
+        let shown = String::from_utf8_lossy(line);
+        // Split from the end as the path may contain `|`
+        let mut parts = line.rsplitn(3, |&b| b == b'|');
+        let (time, rank, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(time), Some(rank), Some(path)) => (time, rank, path),
+            _ => return Err(format!("Expected <path>|<rank>|<time>, got {}", shown)),
+        };
+        entries.push(Entry {
+            path: path_from(path),
+            weight: number(rank).ok_or_else(|| format!("Bad rank in {}", shown))?,
+            last_visit: Some(number(time).ok_or_else(|| format!("Bad time in {}", shown))?),
+        });
+    }
+    Ok(entries)
+}
+
+/// Reads little endian numbers and strings the way bincode writes them
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err(String::from("Unexpected end of zoxide database"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn path(&mut self) -> Result<PathBuf, String> {
+        let len = self.u64()?.try_into().map_err(|_| "Path too long")?;
+        Ok(path_from(self.take(len)?))
+    }
+}
+
+/// zoxide's db.zo is a bincode encoded version number followed by a list of
+/// `(path, rank, last_accessed)`, only version 3 is supported
+fn parse_zoxide(bytes: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut reader = Reader { bytes };
+    let version = reader.u32()?;
+    if version != 3 {
+        return Err(format!("Unsupported zoxide database version {}", version));
+    }
+
+    let count = reader.u64()?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        entries.push(Entry {
+            path: reader.path()?,
+            weight: reader.f64()?,
+            last_visit: Some(reader.u64()?),
+        });
+    }
+    Ok(entries)
+}
+
+/// Scale weights so the highest one becomes max_count, keeping everything at least 1
+fn normalize(entries: Vec<Entry>, max_count: i64, now: u64) -> Vec<(PathBuf, Visit)> {
+    let max_weight = entries.iter().map(|e| e.weight).fold(0.0, f64::max);
+    entries
+        .into_iter()
+        .filter(|e| e.weight > 0.0)
+        .map(|e| {
+            let count = (e.weight / max_weight * max_count as f64).round() as i64;
+            let visit = Visit {
+                count: count.max(1),
+                last_visit: e.last_visit.unwrap_or(now),
+            };
+            (e.path, visit)
+        })
+        .collect()
+}
+
+fn parse(source: &Source, file: &Path) -> Result<Vec<Entry>, String> {
+    let contents = fs::read(file).map_err(|e| e.to_string())?;
+    match source {
+        Source::Autojump => parse_autojump(&contents),
+        Source::Z | Source::Fasd => parse_z(&contents),
+        Source::Zoxide => parse_zoxide(&contents),
+    }
+}
+
+/// Merge history from another jumper into previous visits, gives the number of imported directories.
+///
+/// Weights are scaled so the most used imported directory counts as much as the most visited
+/// directory already saved, or `DEFAULT_MAX_COUNT` visits if nothing is saved yet.
+pub fn import(config: &Config, source: Source, file: &Path) -> error::Result<usize> {
+    let previous_visits = config
+        .previous_visits
+        .clone()
+        .ok_or(Error::NotConfigured("previous_visits"))?;
+    let entries = parse(&source, file).map_err(|message| Error::Import {
+        file: file.to_path_buf(),
+        message,
+    })?;
+
+    let mut imported = 0;
+    update_state(previous_visits, |state| {
+        let max_count = match state.values().map(|v| v.count).max() {
+            Some(max) if max > 0 => max,
+            _ => DEFAULT_MAX_COUNT,
+        };
+        for (path, visit) in normalize(entries, max_count, now()) {
+            let merged = match state.get(&path) {
+                Some(previous) => Visit {
+                    count: previous.count + visit.count,
+                    last_visit: previous.last_visit.max(visit.last_visit),
+                },
+                None => visit,
+            };
+            state.insert(path, merged);
+            imported += 1;
+        }
+    })?;
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::get_current_state;
+    use crate::cache::write_yaml;
+    use crate::config::test_config;
+    use std::env;
+
+    fn entry(path: &str, weight: f64, last_visit: Option<u64>) -> Entry {
+        Entry {
+            path: PathBuf::from(path),
+            weight,
+            last_visit,
+        }
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!("zoxide".parse(), Ok(Source::Zoxide));
+        assert!("other".parse::<Source>().is_err());
+    }
+
+    #[test]
+    fn test_parse_autojump() {
+        let entries = parse_autojump(b"10.5\t/home/me/a\n2.0\t/home/me/b c\n").unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                entry("/home/me/a", 10.5, None),
+                entry("/home/me/b c", 2.0, None)
+            ]
+        );
+        assert!(parse_autojump(b"/home/me/a").is_err());
+    }
+
+    #[test]
+    fn test_parse_z() {
+        let entries = parse_z(b"/home/me/a|12.5|1600000000\n/home/me/b|c|3|1600000001\n").unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                entry("/home/me/a", 12.5, Some(1600000000)),
+                entry("/home/me/b|c", 3.0, Some(1600000001))
+            ]
+        );
+        assert!(parse_z(b"/home/me/a|12").is_err());
+    }
+
+    #[test]
+    fn test_parse_zoxide() {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.extend_from_slice(&10u64.to_le_bytes());
+        bytes.extend_from_slice(b"/home/me/a");
+        bytes.extend_from_slice(&4.5f64.to_le_bytes());
+        bytes.extend_from_slice(&1600000000u64.to_le_bytes());
+
+        let entries = parse_zoxide(&bytes).unwrap();
+        assert_eq!(entries, vec![entry("/home/me/a", 4.5, Some(1600000000))]);
+
+        assert!(parse_zoxide(&bytes[..bytes.len() - 1]).is_err());
+        bytes[0] = 2;
+        assert!(parse_zoxide(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let entries = vec![
+            entry("a", 200.0, None),
+            entry("b", 50.0, Some(5)),
+            entry("c", 0.1, None),
+            entry("d", 0.0, None),
+        ];
+        let counts: Vec<(PathBuf, i64, u64)> = normalize(entries, 10, 100)
+            .into_iter()
+            .map(|(p, v)| (p, v.count, v.last_visit))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (PathBuf::from("a"), 10, 100),
+                (PathBuf::from("b"), 3, 5),
+                (PathBuf::from("c"), 1, 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_merges() {
+        let mut config: Config = test_config();
+        let mut dir = env::temp_dir();
+        dir.push("fj_import_merges");
+        fs::remove_dir_all(dir.clone()).unwrap_or(());
+        fs::create_dir_all(dir.clone()).unwrap();
+        let previous_visits = dir.join("visits.yml");
+        config.previous_visits = Some(previous_visits.clone());
+
+        write_yaml(
+            previous_visits,
+            b"---\nexisting:\n  count: 20\n  last_visit: 1000",
+        );
+        fs::write(dir.join("z"), "existing|5|2000\nnew|10|500\n").unwrap();
+
+        assert_eq!(import(&config, Source::Z, &dir.join("z")).unwrap(), 2);
+
+        let state = get_current_state(config).unwrap();
+        let existing = &state[Path::new("existing")];
+        assert_eq!((existing.count, existing.last_visit), (30, 2000));
+        let new = &state[Path::new("new")];
+        assert_eq!((new.count, new.last_visit), (20, 500));
+    }
+
+    #[test]
+    fn test_import_requires_previous_visits() {
+        let config: Config = test_config();
+        assert!(import(&config, Source::Z, Path::new("nonexisting")).is_err());
+    }
+}
@@ -0,0 +1,527 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::fj_matcher::{check_roots, found_below, walk_below};
+use crate::mounts::Filesystems;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, create_dir_all};
+use std::io::Write;
+use std::ops::Bound;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HEADER: &str = "fastjump-index 2";
+
+/// Directories found below the scan roots together with their modification times.
+///
+/// A directory's modification time changes when entries are added to or removed
+/// from it, so only directories with a changed time need to be read again when
+/// the index is refreshed.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Index {
+    roots: Vec<String>,
+    /// Fingerprint of the settings the index was built with, see `scan_settings`
+    settings: u64,
+    dirs: BTreeMap<PathBuf, u128>,
+}
+
+fn root_paths(config: &Config) -> Vec<String> {
+    config.scan_roots.iter().map(|r| r.path.clone()).collect()
+}
+
+fn sorted(names: &HashSet<String>) -> Vec<&String> {
+    let mut names: Vec<&String> = names.iter().collect();
+    names.sort();
+    names
+}
+
+/// Fingerprint of every setting that changes which directories a scan finds
+///
+/// Hashed with FNV-1a, which unlike the standard hasher gives the same value in every build.
+fn scan_settings(config: &Config) -> u64 {
+    let mut settings = format!(
+        "{:?} {:?} {} {:?} {:?} {} {} {:?} {:?} {:?}",
+        config.max_depth,
+        config.symlinks,
+        config.one_filesystem,
+        sorted(&config.skip_fs_types),
+        config.skip_mounts,
+        config.respect_gitignore,
+        config.scan_hidden,
+        sorted(&config.hidden_allow),
+        sorted(&config.ignores),
+        config.ignore_globs.patterns(),
+    );
+    for root in config.scan_roots.iter() {
+        settings.push_str(&format!(
+            "\n{:?} {:?} {:?} {:?}",
+            root.path,
+            root.max_depth,
+            sorted(&root.ignores),
+            root.ignore_globs.patterns(),
+        ));
+    }
+    settings.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn modified(path: &Path) -> Option<u128> {
+    let time = fs::metadata(path).ok()?.modified().ok()?;
+    Some(time.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+/// Range of paths starting at the first path below dir.
+///
+/// Paths are ordered by component, so everything below dir comes right after it.
+pub(crate) fn below(dir: &Path) -> (Bound<&Path>, Bound<&Path>) {
+    (Bound::Excluded(dir), Bound::Unbounded)
+}
+
+/// Escape bytes so they fit on a single line
+pub(crate) fn escape_line(line: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(line.len());
+    for &byte in line {
+        match byte {
+            b'\\' => res.extend_from_slice(b"\\\\"),
+            b'\n' => res.extend_from_slice(b"\\n"),
+            _ => res.push(byte),
+        }
+    }
+    res
+}
+
+pub(crate) fn unescape_line(line: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(line.len());
+    let mut bytes = line.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'\\' {
+            match bytes.next() {
+                Some(b'n') => res.push(b'\n'),
+                Some(&other) => res.push(other),
+                None => res.push(b'\\'),
+            }
+        } else {
+            res.push(byte);
+        }
+    }
+    res
+}
+
+/// Escape path so it fits on a single line, keeping bytes that are not UTF-8 as they are
+pub(crate) fn escape_path(path: &Path) -> Vec<u8> {
+    escape_line(path.as_os_str().as_bytes())
+}
+
+pub(crate) fn unescape_path(line: &[u8]) -> PathBuf {
+    PathBuf::from(OsString::from_vec(unescape_line(line)))
+}
+
+impl Index {
+    /// Scan the whole directory tree below every scan root
+    pub(crate) fn rebuild(config: &Config) -> Index {
+        let mut index = Index {
+            roots: root_paths(config),
+            settings: scan_settings(config),
+            dirs: BTreeMap::new(),
+        };
+        for root in index.roots.clone() {
+            index.add_subtree(config, Path::new(&root));
+        }
+        index
+    }
+
+    /// Add dir and everything below it
+    fn add_subtree(&mut self, config: &Config, dir: &Path) {
+        let (tx, rx) = channel();
+        walk_below(config.clone(), dir, tx);
+
+        let found = std::iter::once(dir.to_path_buf()).chain(rx.iter());
+        for path in found {
+            if let Some(time) = modified(&path) {
+                self.dirs.insert(path, time);
+            }
+        }
+    }
+
+    /// Remove dir and everything below it
+    fn remove_subtree(&mut self, dir: &Path) {
+        let subtree: Vec<PathBuf> = self
+            .dirs
+            .range::<Path, _>(below(dir))
+            .take_while(|(path, _)| path.starts_with(dir))
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in subtree {
+            self.dirs.remove(&path);
+        }
+        self.dirs.remove(dir);
+    }
+
+    /// Read again directories changed since they were indexed
+    pub(crate) fn refresh(&mut self, config: &Config) {
+        let indexed: Vec<(PathBuf, u128)> =
+            self.dirs.iter().map(|(p, t)| (p.clone(), *t)).collect();
+        let filesystems = Filesystems::new(config);
+
+        for (dir, time) in indexed {
+            // Already removed together with a parent
+            if !self.dirs.contains_key(&dir) {
+                continue;
+            }
+            match modified(&dir) {
+                None => self.remove_subtree(&dir),
+                Some(t) if t != time => {
+                    self.dirs.insert(dir.clone(), t);
+                    self.refresh_children(config, &filesystems, &dir);
+                }
+                Some(_) => (),
+            }
+        }
+    }
+
+    /// Sync the direct children of dir with the file system
+    fn refresh_children(&mut self, config: &Config, filesystems: &Filesystems, dir: &Path) {
+        let indexed: Vec<PathBuf> = self
+            .dirs
+            .range::<Path, _>(below(dir))
+            .take_while(|(path, _)| path.starts_with(dir))
+            .filter(|(path, _)| path.parent() == Some(dir))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        // Directories too deep or on file systems not scanned keep no children
+        let current = found_below(config, filesystems, dir).unwrap_or_default();
+
+        for path in indexed.iter() {
+            if !current.contains(path) {
+                self.remove_subtree(path);
+            }
+        }
+        for path in current.iter() {
+            if !self.dirs.contains_key(path) {
+                self.add_subtree(config, path);
+            }
+        }
+    }
+
+    /// Check if the index was built with the scan settings in config
+    fn built_for(&self, config: &Config) -> bool {
+        self.settings == scan_settings(config)
+    }
+
+    /// Check if path is a root not inside any other root
+    fn is_outer_root(&self, path: &Path) -> bool {
+        self.roots.iter().any(|root| path == Path::new(root))
+            && !self
+                .roots
+                .iter()
+                .any(|root| path != Path::new(root) && path.starts_with(root))
+    }
+
+    /// All indexed directories, not including the scan roots themselves
+    pub(crate) fn paths(&self) -> Vec<PathBuf> {
+        self.dirs
+            .keys()
+            .filter(|path| !self.is_outer_root(path))
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn save(&self, file: &Path) -> std::io::Result<()> {
+        let mut contents = format!("{} {:016x}\n", HEADER, self.settings).into_bytes();
+        for root in self.roots.iter() {
+            contents.extend_from_slice(b"root\t");
+            contents.extend(escape_line(root.as_bytes()));
+            contents.push(b'\n');
+        }
+        for (path, time) in self.dirs.iter() {
+            contents.extend_from_slice(format!("{}\t", time).as_bytes());
+            contents.extend(escape_path(path));
+            contents.push(b'\n');
+        }
+
+        let mut dir = file.to_path_buf();
+        dir.pop();
+        let _ = create_dir_all(dir.as_path());
+
+        // Write to a temporary file first so readers never see a partial index
+        let mut tmp = file.as_os_str().to_owned();
+        tmp.push(format!(".tmp{}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+        fs::File::create(&tmp)?.write_all(&contents)?;
+        fs::rename(&tmp, file)
+    }
+
+    /// Read index from file, gives None if it does not exist or can not be understood
+    pub(crate) fn load(file: &Path) -> Option<Index> {
+        let contents = fs::read(file).ok()?;
+        let mut lines = contents.split(|&b| b == b'\n').filter(|l| !l.is_empty());
+        let header = std::str::from_utf8(lines.next()?).ok()?;
+        let settings = header.strip_prefix(HEADER)?.strip_prefix(' ')?;
+        let settings = u64::from_str_radix(settings, 16).ok()?;
+
+        let mut roots = vec![];
+        let mut dirs = BTreeMap::new();
+        for line in lines {
+            let tab = line.iter().position(|&b| b == b'\t')?;
+            match (&line[..tab], &line[tab + 1..]) {
+                (b"root", root) => roots.push(String::from_utf8(unescape_line(root)).ok()?),
+                (time, path) => {
+                    let time = std::str::from_utf8(time).ok()?.parse().ok()?;
+                    dirs.insert(unescape_path(path), time);
+                }
+            }
+        }
+        Some(Index {
+            roots,
+            settings,
+            dirs,
+        })
+    }
+}
+
+fn is_fresh(file: &Path, max_age: u64) -> bool {
+    let age = fs::metadata(file)
+        .and_then(|m| m.modified())
+        .map(|time| SystemTime::now().duration_since(time).unwrap_or_default());
+    match age {
+        Ok(age) => age < Duration::from_secs(max_age),
+        Err(_) => false,
+    }
+}
+
+fn save(index: &Index, file: &Path) -> Result<()> {
+    index.save(file).map_err(|source| Error::Index {
+        file: file.to_path_buf(),
+        source,
+    })
+}
+
+/// Scan all scan roots, failing like a scan does when none of them can be read
+fn build(config: &Config) -> Result<Index> {
+    check_roots(config)?;
+    Ok(Index::rebuild(config))
+}
+
+/// Directories from the configured index, refreshed first if older than index_max_age.
+///
+/// An index built with other scan roots or settings affecting the scan is rebuilt.
+/// Gives None when no index is configured or it has not been built yet.
+pub(crate) fn load_fresh(config: &Config) -> Option<Vec<PathBuf>> {
+    let file = config.index.as_ref()?;
+    let mut index = Index::load(file)?;
+    if !index.built_for(config) {
+        // A scan root that can not be read is reported by the scan used instead
+        index = build(config).ok()?;
+        if let Err(e) = save(&index, file) {
+            eprintln!("Error: {}", e);
+        }
+    } else if !is_fresh(file, config.index_max_age) {
+        index.refresh(config);
+        // The refreshed directories are still good to match against
+        if let Err(e) = save(&index, file) {
+            eprintln!("Error: {}", e);
+        }
+    }
+    Some(index.paths())
+}
+
+/// Build a new index from scratch and save it, gives the number of indexed directories
+pub fn rebuild(config: &Config) -> Result<usize> {
+    let file = config.index.as_ref().ok_or(Error::NotConfigured("index"))?;
+    let index = build(config)?;
+    save(&index, file)?;
+    Ok(index.paths().len())
+}
+
+/// Refresh the saved index, building it if there is none, gives the number of indexed directories
+pub fn update(config: &Config) -> Result<usize> {
+    let file = config.index.as_ref().ok_or(Error::NotConfigured("index"))?;
+    let index = match Index::load(file) {
+        Some(mut index) if index.built_for(config) => {
+            index.refresh(config);
+            index
+        }
+        _ => build(config)?,
+    };
+    save(&index, file)?;
+    Ok(index.paths().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{test_config, ScanRoot};
+    use crate::ignores::GlobIgnoresBuilder;
+    use std::env;
+
+    fn setup(name: &str, folders: Vec<&str>) -> (Config, PathBuf) {
+        let mut config: Config = test_config();
+        let mut dir = env::temp_dir();
+        dir.push(format!("{}_fj_index_tests", name));
+        fs::remove_dir_all(dir.clone()).unwrap_or(());
+        fs::create_dir_all(dir.clone()).unwrap();
+        for folder in folders {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+        }
+        config.scan_roots = vec![ScanRoot::new(dir.to_str().unwrap())];
+        config.index = Some(dir.join("index.txt"));
+        (config, dir)
+    }
+
+    fn relative(index: &Index, dir: &Path) -> Vec<String> {
+        index
+            .paths()
+            .iter()
+            .map(|p| p.strip_prefix(dir).unwrap().to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_rebuild_respects_ignores() {
+        let (mut config, dir) = setup("rebuild", vec!["a/b", "node_modules/c", ".hidden"]);
+        config.ignores.insert(String::from("node_modules"));
+
+        let index = Index::rebuild(&config);
+        assert_eq!(relative(&index, &dir), vec!["a", "a/b"]);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let (config, dir) = setup("save_load", vec!["a", "with\nnewline", "back\\slash"]);
+        let index = Index::rebuild(&config);
+        index.save(&dir.join("index.txt")).unwrap();
+
+        let loaded = Index::load(&dir.join("index.txt")).unwrap();
+        assert_eq!(loaded, index);
+        assert_eq!(loaded.paths().len(), 3);
+    }
+
+    #[test]
+    fn test_save_and_load_non_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let (config, dir) = setup("non_utf8", vec![]);
+        fs::create_dir(dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9"))).unwrap();
+        let index = Index::rebuild(&config);
+        index.save(&dir.join("index.txt")).unwrap();
+
+        let loaded = Index::load(&dir.join("index.txt")).unwrap();
+        assert_eq!(
+            loaded.paths(),
+            vec![dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9"))]
+        );
+    }
+
+    #[test]
+    fn test_multiple_roots() {
+        let (mut config, dir) = setup("roots", vec!["a/b", "a/inner/c", "d/e"]);
+        let root = |path: &str| ScanRoot::new(dir.join(path).to_str().unwrap());
+        config.scan_roots = vec![root("a"), root("a/inner"), root("d")];
+
+        let index = Index::rebuild(&config);
+        assert_eq!(
+            relative(&index, &dir),
+            vec!["a/b", "a/inner", "a/inner/c", "d/e"]
+        );
+
+        index.save(&dir.join("index.txt")).unwrap();
+        assert_eq!(Index::load(&dir.join("index.txt")).unwrap(), index);
+
+        assert_eq!(update(&config).unwrap(), 4);
+        config.scan_roots.pop();
+        assert_eq!(load_fresh(&config).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_load_missing_or_broken() {
+        let (_config, dir) = setup("load_broken", vec![]);
+        assert_eq!(Index::load(&dir.join("index.txt")), None);
+
+        fs::write(dir.join("index.txt"), "something else\n").unwrap();
+        assert_eq!(Index::load(&dir.join("index.txt")), None);
+    }
+
+    #[test]
+    fn test_refresh_finds_changes() {
+        let (config, dir) = setup("refresh", vec!["a/b", "c/d", "e"]);
+        let mut index = Index::rebuild(&config);
+
+        fs::create_dir_all(dir.join("a/new/deep")).unwrap();
+        fs::remove_dir_all(dir.join("c")).unwrap();
+        index.refresh(&config);
+
+        assert_eq!(
+            relative(&index, &dir),
+            vec!["a", "a/b", "a/new", "a/new/deep", "e"]
+        );
+    }
+
+    #[test]
+    fn test_refresh_respects_scan_limits() {
+        let (mut config, dir) = setup("refresh_limits", vec!["a"]);
+        config.max_depth = Some(1);
+        config.skip_mounts = vec![dir.join("mnt")];
+        let mut index = Index::rebuild(&config);
+
+        fs::create_dir_all(dir.join("a/b/c")).unwrap();
+        fs::create_dir_all(dir.join("mnt/share")).unwrap();
+        fs::create_dir_all(dir.join("d")).unwrap();
+        index.refresh(&config);
+
+        assert_eq!(index, Index::rebuild(&config));
+        assert_eq!(relative(&index, &dir), vec!["a", "d"]);
+    }
+
+    #[test]
+    fn test_load_fresh() {
+        let (mut config, dir) = setup("load_fresh", vec!["a"]);
+        assert_eq!(load_fresh(&config), None);
+
+        assert_eq!(rebuild(&config).unwrap(), 1);
+        fs::create_dir_all(dir.join("b")).unwrap();
+        assert_eq!(load_fresh(&config).unwrap().len(), 1);
+
+        config.index_max_age = 0;
+        assert_eq!(load_fresh(&config).unwrap().len(), 2);
+
+        config.ignores.insert("b".to_string());
+        assert_eq!(load_fresh(&config).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_settings_change_rebuilds() {
+        let (mut config, dir) = setup("settings", vec!["a/b/c"]);
+        assert_eq!(rebuild(&config).unwrap(), 3);
+        let built = Index::load(&dir.join("index.txt")).unwrap();
+        assert!(built.built_for(&config));
+
+        config.max_depth = Some(1);
+        assert!(!built.built_for(&config));
+        assert_eq!(update(&config).unwrap(), 1);
+        config.max_depth = None;
+
+        let mut globs = GlobIgnoresBuilder::default();
+        globs.add("c", "").unwrap();
+        config.scan_roots[0].ignore_globs = globs.build();
+        assert_eq!(load_fresh(&config).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_rebuild_fails_on_missing_root() {
+        let (mut config, dir) = setup("missing_root", vec![]);
+        config.scan_roots = vec![ScanRoot::new(dir.join("gone").to_str().unwrap())];
+        assert!(matches!(rebuild(&config), Err(Error::Traversal { .. })));
+        assert!(update(&config).is_err());
+        assert_eq!(load_fresh(&config), None);
+    }
+
+    #[test]
+    fn test_update_builds_missing_index() {
+        let (config, _dir) = setup("update", vec!["a", "b"]);
+        assert_eq!(update(&config).unwrap(), 2);
+        assert_eq!(update(&config).unwrap(), 2);
+    }
+}
@@ -0,0 +1,48 @@
+//! Fuzzy matching of directories, the library behind the `fastjump` command.
+//!
+//! Load a [`config::Config`], then find the best matching directories for a pattern with
+//! [`fj_matcher::matcher_list`] and record where the user went with [`cache::save`].
+//! Failures are returned as an [`error::Error`]:
+//!
+//! ```no_run
+//! use fastjump::{cache, config, fj_matcher};
+//!
+//! fn main() -> fastjump::error::Result<()> {
+//!     let config = config::get_config(None)?;
+//!     for result in fj_matcher::matcher_list(config.clone(), String::from("proj"), 5)? {
+//!         println!("{} {} {}", result.score, result.bonus, result.path.display());
+//!     }
+//!     if let Some(best) = fj_matcher::matcher_list(config.clone(), String::from("proj"), 1)?.pop() {
+//!         cache::save(config, best.path)?;
+//!     }
+//!     Ok(())
+//! }
+//! ```
+//!
+//! [`fj_matcher::stream`] gives the scored directories while the scan is running instead.
+#![cfg_attr(all(feature = "nightly", test), feature(test))]
+
+#[cfg(all(feature = "nightly", test))]
+extern crate test;
+
+/// The visit store: previous visits saved to `previous_visits` and the bonuses they give
+pub mod cache;
+/// Reading `~/.fastjump.yml` or another config file
+pub mod config;
+/// In-memory directory tree served over `daemon_socket`, and the client asking it
+pub mod daemon;
+/// The errors fastjump reports and the exit codes they map to
+pub mod error;
+/// Scanning the scan roots and fuzzy matching directories against a pattern
+pub mod fj_matcher;
+/// Ignore files and glob patterns deciding which directories are skipped
+mod ignores;
+/// Importing history from other directory jumpers into the visit store
+pub mod import;
+/// The persistent directory index at `index`
+pub mod index;
+mod mounts;
+/// Interactive picking of a directory on the terminal
+pub mod picker;
+/// Shell integration scripts printed by `fastjump init`
+pub mod shell;
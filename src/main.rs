@@ -1,14 +1,12 @@
-#![cfg_attr(all(feature = "nightly", test), feature(test))]
-
-#[cfg(all(feature = "nightly", test))]
-extern crate test;
-
-mod cache;
-mod config;
-mod fj_matcher;
-use cache::save;
-use config::{get_config_pb, Config};
-use std::path::PathBuf;
+use fastjump::cache::{self, save};
+use fastjump::config::{get_config_pb, Config};
+use fastjump::error::{Error, Result};
+use fastjump::{daemon, fj_matcher, import, index, picker, shell};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use structopt::clap::{self, ErrorKind};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -20,8 +18,10 @@ struct Cli {
     /// If passing `--save-visit` the location to save, otherwise will be used to change directories
     ///
     /// See help for `--save-visit` for more information how pattern is used in that case.
-    /// Otherwise fastjump will attempt to match all existing directories from `scan_root` (if specified in config) or `HOME` if not configured. All directories will be fuzzy matched against the pattern and the best option will be printed in a way that it can be directly used by `cd`, `cd $(fastjump <some pattern>)`. If no good match is found it will print `.` and `cd` will change to the current directory.
-    pattern: String,
+    /// Put the pattern after `--` when it starts with `-` or is named like a subcommand, as in `fastjump -- index`.
+    /// Otherwise fastjump will attempt to match all existing directories from `scan_root` or `scan_roots` (if specified in config) or `HOME` if not configured. All directories will be fuzzy matched against the pattern and the best option will be printed in a way that it can be directly used by `cd`, `cd $(fastjump <some pattern>)`. See `--raw`, `--quote` and `--print0` for other forms. If no good match is found it will print `.` and `cd` will change to the current directory.
+    #[structopt(parse(from_os_str))]
+    pattern: Option<OsString>,
 
     #[structopt(long = "--config", parse(from_os_str))]
     /// Use a non standard configuration file, default: `~/.fastjump.yml`
@@ -35,44 +35,551 @@ struct Cli {
     ///
     /// Saves the location in pattern to configured cache file. See help for `--config` for how to configure.
     /// Will update the cache file and give the saved location a better match when matching.
+    /// Relative locations are saved as absolute paths with symlinks resolved.
     save_visit: bool,
+
+    #[structopt(short, long = "--list")]
+    /// List up to this many of the best matches instead of changing directory
+    ///
+    /// Prints one match per line, best first, with the fuzzy match score, the bonus from previous visits and the path separated by tabs.
+    /// Useful to see the runner-up candidates when the best match is not the expected directory. Nothing is saved to the cache file when listing.
+    list: Option<usize>,
+
+    #[structopt(short, long = "--interactive")]
+    /// Pick the directory interactively, using pattern as the initial query
+    ///
+    /// Candidates are listed on the terminal while scanning. Refine the query by typing, move with the arrow keys and press enter to choose or escape to cancel.
+    /// Only the chosen path is printed to stdout so `cd "$(fastjump --raw -i <some pattern>)"` works as usual.
+    interactive: bool,
+
+    #[structopt(long = "--raw")]
+    /// Print paths as they are instead of escaping spaces
+    ///
+    /// Use when the output is quoted, as in `cd "$(fastjump --raw <some pattern>)"`. The scripts from `fastjump init` use this.
+    raw: bool,
+
+    #[structopt(long = "--quote", conflicts_with_all = &["raw", "print0"])]
+    /// Quote paths for a POSIX shell
+    ///
+    /// Paths with characters special to the shell, like `'`, `$`, `*` or newlines, are put in single quotes, ready for `eval "cd $(fastjump --quote <some pattern>)"`.
+    quote: bool,
+
+    #[structopt(short = "0", long = "--print0")]
+    /// Print paths as they are and end each line with a NUL byte instead of a newline
+    ///
+    /// Works with any directory name, even ones with newlines, for example `fastjump --print0 --list 10 proj | xargs -0`.
+    print0: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Manage the persistent directory index
+    ///
+    /// Requires `index` to be configured. When an index exists it is used instead of scanning the scan roots on every jump.
+    /// Directories that changed since the index was written are rescanned when it is older than `index_max_age` seconds.
+    Index(IndexCommand),
+    /// Keep the directory tree in memory and answer queries over `daemon_socket`
+    ///
+    /// Requires `daemon_socket` to be configured. Runs until killed, watching the scan roots for created, removed and renamed directories.
+    /// Whenever the socket is present fastjump asks the daemon instead of scanning.
+    Daemon,
+    /// Import visit history from another directory jumper
+    ///
+    /// Requires `previous_visits` to be configured. Entries are merged with the visits already saved,
+    /// with weights scaled so the most used imported directory counts as much as the most visited directory saved so far.
+    Import {
+        #[structopt(long = "--from")]
+        /// Jumper the history comes from: autojump, z, fasd or zoxide
+        from: import::Source,
+
+        #[structopt(parse(from_os_str))]
+        /// Database of the other jumper, for example `~/.local/share/autojump/autojump.txt`, `~/.z` or zoxide's `db.zo`
+        file: PathBuf,
+    },
+    /// Inspect and edit the saved previous visits
+    ///
+    /// Requires `previous_visits` to be configured.
+    Cache(CacheCommand),
+    /// Print shell integration for bash, zsh, fish or nushell
+    ///
+    /// Defines a `j <pattern>` function to jump, a hook saving visited directories and completions for `j`.
+    /// Load it from the shell config, for example `eval "$(fastjump init zsh)"` in `~/.zshrc`.
+    /// See https://github.com/mattiaslundberg/fastjump#shell-integration for all shells.
+    Init { shell: shell::Shell },
+}
+
+#[derive(StructOpt, Debug)]
+enum CacheCommand {
+    /// List saved visits with their weights, most visited first
+    List {
+        #[structopt(long = "--json")]
+        /// Print a JSON array of objects with path, count and last_visit instead
+        json: bool,
+    },
+    /// Forget all visits to a directory
+    Remove {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Set the weight of a directory, adding it if it was never visited
+    Set {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        weight: i64,
+    },
+    /// Forget visits to directories
+    Purge {
+        #[structopt(long = "--missing")]
+        /// Only purge directories that no longer exist
+        missing: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum IndexCommand {
+    /// Scan all scan roots and write a new index
+    Rebuild,
+    /// Rescan only directories changed since the index was written, builds the index if missing
+    Update,
 }
 
 #[cfg_attr(tarpaulin, skip)]
 fn main() {
-    let args: Cli = Cli::from_args();
+    let args = parse_args(std::env::args_os().collect());
+    if let Err(e) = run(args) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// Parse the command line, taking the argument after a top level `--` as the pattern
+///
+/// clap matches subcommand names even after `--`, so `fastjump -- index` would not jump to a directory named like a subcommand.
+fn parse_args(args: Vec<OsString>) -> Cli {
+    if let Some(dash) = args.iter().position(|arg| arg == "--") {
+        if args.len() == dash + 2 {
+            if let Ok(mut cli) = Cli::from_iter_safe(&args[..dash]) {
+                if cli.command.is_none() && cli.pattern.is_none() {
+                    cli.pattern = Some(args[dash + 1].clone());
+                    return cli;
+                }
+            }
+        }
+    }
+    Cli::from_iter(args)
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn run(args: Cli) -> Result<()> {
+    // The script is evaluated by the shell, so nothing else may be printed
+    if let Some(Command::Init { shell }) = args.command {
+        print!("{}", shell::init(shell));
+        return Ok(());
+    }
     let config_file = args.config_file;
-    let config = get_config_pb(config_file);
+    let config = get_config_pb(config_file)?;
+
+    if let Some(command) = args.command {
+        return run_command(config, command);
+    }
+    let pattern = match args.pattern {
+        Some(pattern) => pattern,
+        None => clap::Error::with_description(
+            "The following required arguments were not provided:\n    <pattern>",
+            ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
 
     if args.save_visit {
-        save(config, args.pattern);
-        return;
+        return save_given(config, &pattern);
+    }
+    // Directories are matched by their lossy display, so the pattern only needs to be text
+    let pattern = pattern.to_string_lossy().into_owned();
+    let output = Output::from_args(args.raw, args.quote, args.print0);
+    if let Some(limit) = args.list {
+        for line in list(config, pattern, limit, output)? {
+            output.print(&line);
+        }
+        return Ok(());
+    }
+    if args.interactive {
+        return pick(config, pattern, output);
+    }
+    change(config, pattern, output)?;
+    Ok(())
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn run_command(config: Config, command: Command) -> Result<()> {
+    match command {
+        Command::Index(index_command) => {
+            let count = match index_command {
+                IndexCommand::Rebuild => index::rebuild(&config)?,
+                IndexCommand::Update => index::update(&config)?,
+            };
+            println!("Indexed {} directories", count);
+        }
+        Command::Import { from, file } => {
+            let count = import::import(&config, from, &file)?;
+            println!("Imported {} directories", count);
+        }
+        Command::Cache(cache_command) => {
+            for line in run_cache_command(config, cache_command)? {
+                print_line(&line);
+            }
+        }
+        Command::Init { .. } => unreachable!("init is handled before reading config"),
+        Command::Daemon => daemon::run(config)?,
     }
-    change(config, args.pattern);
+    Ok(())
 }
 
-fn change(config: Config, pattern: String) -> String {
-    let best_result: String = fj_matcher::matcher(config.clone(), pattern);
+fn run_cache_command(config: Config, command: CacheCommand) -> Result<Vec<OsString>> {
+    let previous_visits = config
+        .previous_visits
+        .clone()
+        .ok_or(Error::NotConfigured("previous_visits"))?;
 
-    save(config, best_result.clone());
-    println!("{}", best_result);
-    best_result
+    match command {
+        CacheCommand::List { json } => {
+            let visits = cache::sorted_visits(config)?;
+            if json {
+                return Ok(vec![OsString::from(cache::visits_to_json(&visits))]);
+            }
+            Ok(visits
+                .iter()
+                .map(|(path, visit)| {
+                    let mut line = OsString::from(format!("{}\t", visit.count));
+                    line.push(path);
+                    line
+                })
+                .collect())
+        }
+        CacheCommand::Remove { path } => {
+            // Entries saved before locations were resolved can still be removed as written
+            let location = cache::resolve_location(&path);
+            if cache::remove(previous_visits.clone(), &location)?
+                || (location != path && cache::remove(previous_visits, &path)?)
+            {
+                Ok(vec![])
+            } else {
+                Err(Error::Usage(format!(
+                    "{} is not in the cache",
+                    path.display()
+                )))
+            }
+        }
+        CacheCommand::Set { path, weight } => {
+            cache::set_count(previous_visits, cache::resolve_location(&path), weight)?;
+            Ok(vec![])
+        }
+        CacheCommand::Purge { missing: false } => Err(Error::Usage(String::from(
+            "Nothing to purge, pass --missing to purge missing directories",
+        ))),
+        CacheCommand::Purge { missing: true } => Ok(cache::purge_missing(previous_visits)?
+            .into_iter()
+            .map(PathBuf::into_os_string)
+            .collect()),
+    }
+}
+
+/// Remember the visit to the directory given to `--save-visit`, relative to the current directory
+fn save_given(config: Config, location: &OsStr) -> Result<()> {
+    save(config, cache::resolve_location(Path::new(location)))
+}
+
+/// Remember the visit to path, a failure is reported but does not stop the jump
+fn save_visit(config: Config, path: PathBuf) {
+    if let Err(e) = save(config, path) {
+        eprintln!("Error: {}", e);
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn pick(config: Config, pattern: String, output: Output) -> Result<()> {
+    match picker::pick(config.clone(), pattern)? {
+        Some(path) => {
+            save_visit(config, path.clone());
+            output.print(&output.path(&path));
+        }
+        None => output.print(OsStr::new(".")),
+    }
+    Ok(())
+}
+
+/// Print line as its bytes, so paths that are not UTF-8 come out unchanged
+fn print_line(line: &OsStr) {
+    write_line(line, b'\n');
+}
+
+fn write_line(line: &OsStr, end: u8) {
+    let mut bytes = line.as_bytes().to_vec();
+    bytes.push(end);
+    // Like a pipe closed by the reader, there is nobody left to tell about a failed write
+    let _ = io::stdout().write_all(&bytes);
+}
+
+/// How paths are written to stdout
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Output {
+    /// Spaces escaped for `cd $(...)`, one per line
+    Escaped,
+    /// Quoted for a POSIX shell, one per line
+    Quoted,
+    /// As they are, one per line
+    Raw,
+    /// As they are, each ended by a NUL byte
+    Print0,
+}
+
+impl Output {
+    fn from_args(raw: bool, quote: bool, print0: bool) -> Output {
+        if print0 {
+            Output::Print0
+        } else if raw {
+            Output::Raw
+        } else if quote {
+            Output::Quoted
+        } else {
+            Output::Escaped
+        }
+    }
+
+    fn path(self, path: &Path) -> OsString {
+        match self {
+            Output::Escaped => fj_matcher::escape(path),
+            Output::Quoted => fj_matcher::shell_quote(path),
+            Output::Raw | Output::Print0 => path.as_os_str().to_owned(),
+        }
+    }
+
+    fn print(self, line: &OsStr) {
+        match self {
+            Output::Print0 => write_line(line, b'\0'),
+            Output::Escaped | Output::Quoted | Output::Raw => print_line(line),
+        }
+    }
+}
+
+fn list(config: Config, pattern: String, limit: usize, output: Output) -> Result<Vec<OsString>> {
+    Ok(fj_matcher::matcher_list(config, pattern, limit)?
+        .iter()
+        .map(|r| {
+            let mut line = OsString::from(format!("{}\t{}\t", r.score, r.bonus));
+            line.push(output.path(&r.path));
+            line
+        })
+        .collect())
+}
+
+fn change(config: Config, pattern: String, output: Output) -> Result<OsString> {
+    let best_result = fj_matcher::best_match(config.clone(), pattern)?;
+    let line = output.path(&best_result);
+
+    save_visit(config, best_result);
+    output.print(&line);
+    Ok(line)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use config::test_config;
+    use fastjump::config::get_config;
+    use std::path::Path;
+
+    fn test_config() -> Config {
+        get_config(Some(Path::new("test_configs/missing_ignores.yml"))).unwrap()
+    }
 
     #[test]
     fn test_good_match() {
         let pattern = String::from("empty");
-        assert_eq!(change(test_config(), pattern), "test_configs/empty")
+        assert_eq!(
+            change(test_config(), pattern, Output::Escaped).unwrap(),
+            "test_configs/empty"
+        )
+    }
+
+    #[test]
+    fn test_ignored_by_fjignore() {
+        let pattern = String::from("ignored");
+        assert_eq!(
+            change(test_config(), pattern, Output::Escaped).unwrap(),
+            "."
+        )
     }
 
     #[test]
     fn test_no_match() {
         let pattern = String::from("nonexisting");
-        assert_eq!(change(test_config(), pattern), ".")
+        assert_eq!(
+            change(test_config(), pattern, Output::Escaped).unwrap(),
+            "."
+        )
+    }
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_parse_pattern_or_command() {
+        let args = Cli::from_iter(vec!["fastjump", "proj"]);
+        assert_eq!(args.pattern, Some(OsString::from("proj")));
+        assert!(args.command.is_none());
+
+        let args = Cli::from_iter(vec!["fastjump", "index", "rebuild"]);
+        assert_eq!(args.pattern, None);
+        assert!(args.command.is_some());
+
+        let args = Cli::from_iter(vec!["fastjump", "init", "fish"]);
+        match args.command {
+            Some(Command::Init { shell }) => assert_eq!(shell, shell::Shell::Fish),
+            other => panic!("Expected init command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cache_commands() {
+        let mut config = test_config();
+        assert!(run_cache_command(config.clone(), CacheCommand::List { json: false }).is_err());
+
+        let mut previous_visits = std::env::temp_dir();
+        previous_visits.push("fj_main_cache_commands.yml");
+        std::fs::remove_file(previous_visits.clone()).unwrap_or(());
+        config.previous_visits = Some(previous_visits);
+
+        let set = |path: &str, weight| CacheCommand::Set {
+            path: PathBuf::from(path),
+            weight,
+        };
+        let current = std::env::current_dir().unwrap();
+        let empty = current.join("test_configs/empty").canonicalize().unwrap();
+        let missing = current.join("test_configs/missing");
+        run_cache_command(config.clone(), set("test_configs/empty", 2)).unwrap();
+        run_cache_command(config.clone(), set("test_configs/../test_configs/empty", 3)).unwrap();
+        run_cache_command(config.clone(), set("test_configs/missing", 5)).unwrap();
+        let listed = run_cache_command(config.clone(), CacheCommand::List { json: false });
+        let line = |count: &str, path: &Path| {
+            let mut line = OsString::from(count);
+            line.push(path);
+            line
+        };
+        assert_eq!(
+            listed.unwrap(),
+            vec![line("5\t", &missing), line("3\t", &empty)]
+        );
+
+        let purge = CacheCommand::Purge { missing: false };
+        assert!(run_cache_command(config.clone(), purge).is_err());
+        let purge = CacheCommand::Purge { missing: true };
+        let purged = run_cache_command(config.clone(), purge).unwrap();
+        assert_eq!(purged, vec![missing]);
+
+        let remove = |path: &str| CacheCommand::Remove {
+            path: PathBuf::from(path),
+        };
+        assert!(run_cache_command(config.clone(), remove("test_configs/empty")).is_ok());
+        assert!(run_cache_command(config.clone(), remove("test_configs/empty")).is_err());
+        let listed = run_cache_command(config, CacheCommand::List { json: true });
+        assert_eq!(listed.unwrap(), vec!["[]"]);
+    }
+
+    #[test]
+    fn test_save_given_resolves_location() {
+        let mut config = test_config();
+        let mut previous_visits = std::env::temp_dir();
+        previous_visits.push("fj_main_save_given.yml");
+        std::fs::remove_file(previous_visits.clone()).unwrap_or(());
+        config.previous_visits = Some(previous_visits);
+
+        save_given(config.clone(), OsStr::new("test_configs/empty")).unwrap();
+        save_given(config.clone(), OsStr::new("test_configs/./empty/")).unwrap();
+        let listed = run_cache_command(config, CacheCommand::List { json: false }).unwrap();
+        let empty = std::env::current_dir()
+            .unwrap()
+            .join("test_configs/empty")
+            .canonicalize()
+            .unwrap();
+        let mut line = OsString::from("2\t");
+        line.push(empty);
+        assert_eq!(listed, vec![line]);
+    }
+
+    #[test]
+    fn test_output_modes() {
+        let path = Path::new("/src/R&D it's");
+        assert_eq!(
+            Output::from_args(false, false, false).path(path),
+            "/src/R&D\\ it's"
+        );
+        assert_eq!(Output::from_args(true, false, false).path(path), path);
+        assert_eq!(
+            Output::from_args(false, true, false).path(path),
+            "'/src/R&D it'\\''s'"
+        );
+        assert_eq!(Output::from_args(false, false, true), Output::Print0);
+        assert_eq!(Output::Print0.path(Path::new("a\nb")), "a\nb");
+    }
+
+    #[test]
+    fn test_quote_conflicts_with_raw() {
+        assert!(Cli::from_iter_safe(vec!["fastjump", "--quote", "--raw", "proj"]).is_err());
+        assert!(
+            Cli::from_iter_safe(vec!["fastjump", "--quote", "proj"])
+                .unwrap()
+                .quote
+        );
+    }
+
+    #[test]
+    fn test_parse_print0() {
+        let args = Cli::from_iter(vec!["fastjump", "-0", "--list", "3", "proj"]);
+        assert!(args.print0);
+        assert_eq!(args.list, Some(3));
+    }
+
+    #[test]
+    fn test_parse_pattern_after_double_dash() {
+        let args = parse_args(os_args(&["fastjump", "--raw", "--", "-proj"]));
+        assert_eq!(args.pattern, Some(OsString::from("-proj")));
+        assert!(args.raw);
+    }
+
+    #[test]
+    fn test_parse_subcommand_name_as_pattern() {
+        for name in &["index", "cache", "daemon", "import", "init"] {
+            let args = parse_args(os_args(&["fastjump", "--raw", "--", name]));
+            assert_eq!(args.pattern, Some(OsString::from(name)));
+            assert!(args.command.is_none());
+        }
+        let args = parse_args(os_args(&["fastjump", "cache", "remove", "--", "-old"]));
+        match args.command {
+            Some(Command::Cache(CacheCommand::Remove { path })) => {
+                assert_eq!(path, PathBuf::from("-old"))
+            }
+            other => panic!("Expected cache remove command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_list_matches() {
+        let pattern = String::from("empty");
+        let lines = list(test_config(), pattern, 3, Output::Escaped).unwrap();
+        assert!(!lines.is_empty());
+        assert!(lines[0].as_bytes().ends_with(b"\ttest_configs/empty"));
+    }
+
+    #[test]
+    fn test_list_no_match() {
+        let pattern = String::from("nonexisting");
+        assert_eq!(
+            list(test_config(), pattern, 3, Output::Escaped).unwrap(),
+            Vec::<OsString>::new()
+        )
     }
 }
@@ -0,0 +1,167 @@
+use crate::config::Config;
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const MOUNTS_FILE: &str = "/proc/self/mounts";
+
+/// Undo the octal escapes used for spaces, tabs, newlines and backslashes in mounts files
+fn unescape_mount(field: &str) -> String {
+    let mut res = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        res.push_str(&rest[..i]);
+        let code = rest
+            .get(i + 1..i + 4)
+            .and_then(|c| u8::from_str_radix(c, 8).ok());
+        match code {
+            Some(c) => {
+                res.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                res.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Mount point and filesystem type for each line of a mounts file like `/proc/self/mounts`
+fn parse_mounts(contents: &str) -> Vec<(PathBuf, String)> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let (_device, mount_point, fs_type) = (fields.next()?, fields.next()?, fields.next()?);
+            Some((
+                PathBuf::from(unescape_mount(mount_point)),
+                String::from(fs_type),
+            ))
+        })
+        .collect()
+}
+
+/// Decides which file systems the scan may continue into
+#[derive(Default)]
+pub struct Filesystems {
+    one_filesystem: bool,
+    /// Mount points from skip_mounts and mounts with a type in skip_fs_types
+    skipped: HashSet<PathBuf>,
+}
+
+impl Filesystems {
+    /// Mounted file systems are only known on Linux, elsewhere skip_fs_types has no effect
+    pub fn new(config: &Config) -> Filesystems {
+        let mut skipped: HashSet<PathBuf> = config.skip_mounts.iter().cloned().collect();
+        if !config.skip_fs_types.is_empty() {
+            let contents = fs::read_to_string(MOUNTS_FILE).unwrap_or_default();
+            for (mount_point, fs_type) in parse_mounts(&contents) {
+                if config.skip_fs_types.contains(&fs_type) {
+                    skipped.insert(mount_point);
+                }
+            }
+        }
+        Filesystems {
+            one_filesystem: config.one_filesystem,
+            skipped,
+        }
+    }
+
+    fn tracks_devices(&self) -> bool {
+        self.one_filesystem || !self.skipped.is_empty()
+    }
+
+    /// Check if dir is a mount point to skip, without touching the file system so a hanging
+    /// network mount does not stall the scan
+    pub fn is_skipped(&self, dir: &Path) -> bool {
+        self.skipped.contains(dir)
+    }
+
+    /// Check if path is on a mount point to skip, without touching the file system
+    pub fn is_below_skipped(&self, path: &Path) -> bool {
+        self.skipped.iter().any(|mount| path.starts_with(mount))
+    }
+
+    /// Device id of dir, 0 when devices are not tracked
+    pub fn device(&self, dir: &Path) -> u64 {
+        if !self.tracks_devices() {
+            return 0;
+        }
+        fs::metadata(dir).map(|m| m.dev()).unwrap_or(0)
+    }
+
+    /// Check if the scan may continue into dir from its parent on device parent_dev,
+    /// gives the device of dir if it may
+    pub fn enter(&self, parent_dev: u64, dir: &Path) -> Option<u64> {
+        if !self.tracks_devices() {
+            return Some(0);
+        }
+        let dev = fs::metadata(dir).ok()?.dev();
+        if dev == parent_dev {
+            return Some(dev);
+        }
+        if self.one_filesystem {
+            return None;
+        }
+        // Mount points are listed with their real path, which dir may not be
+        match fs::canonicalize(dir) {
+            Ok(real) if self.skipped.contains(&real) => None,
+            _ => Some(dev),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    #[test]
+    fn test_parse_mounts() {
+        let contents = "proc /proc proc rw 0 0\n\
+                        server:/export /mnt/with\\040space nfs4 rw 0 0\n\
+                        broken\n";
+        assert_eq!(
+            parse_mounts(contents),
+            vec![
+                (PathBuf::from("/proc"), String::from("proc")),
+                (PathBuf::from("/mnt/with space"), String::from("nfs4"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unescape_mount() {
+        assert_eq!(unescape_mount("a\\011b\\134c"), "a\tb\\c");
+        assert_eq!(unescape_mount("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn test_skipped() {
+        let mut config = test_config();
+        config.skip_mounts = vec![PathBuf::from("/mnt/share")];
+        let filesystems = Filesystems::new(&config);
+        assert!(filesystems.is_skipped(Path::new("/mnt/share")));
+        assert!(!filesystems.is_skipped(Path::new("/mnt")));
+
+        let untracked = Filesystems::new(&test_config());
+        assert_eq!(untracked.enter(0, Path::new("/nonexisting")), Some(0));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_one_filesystem() {
+        let mut config = test_config();
+        config.one_filesystem = true;
+        let filesystems = Filesystems::new(&config);
+        let root = filesystems.device(Path::new("/"));
+        let proc = filesystems.device(Path::new("/proc"));
+
+        assert_eq!(filesystems.enter(root, Path::new("/proc")), None);
+        assert_eq!(filesystems.enter(proc, Path::new("/proc/sys")), Some(proc));
+    }
+}
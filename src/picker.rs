@@ -0,0 +1,336 @@
+use crate::cache::get_visit_bonuses;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::fj_matcher::{insert_ranked, scan, score_path, MatchResult};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{
+    self, disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
+    LeaveAlternateScreen,
+};
+use crossterm::{execute, queue};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use linked_hash_map::LinkedHashMap;
+use std::io::{stderr, Stderr, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+/// What to do after a key has been handled
+#[derive(Debug, PartialEq)]
+enum Action {
+    Continue,
+    Choose,
+    Cancel,
+}
+
+/// State of the picker, kept separate from the terminal so it can be tested
+struct Picker {
+    query: String,
+    selected: usize,
+    candidates: Vec<PathBuf>,
+    results: Vec<MatchResult>,
+    scanning: bool,
+}
+
+impl Picker {
+    fn new(query: String) -> Picker {
+        Picker {
+            query,
+            selected: 0,
+            candidates: vec![],
+            results: vec![],
+            scanning: true,
+        }
+    }
+
+    /// Read all candidates found so far without blocking, returns true if any was added
+    fn receive(&mut self, rx: &Receiver<PathBuf>) -> bool {
+        let mut received = false;
+        loop {
+            match rx.try_recv() {
+                Ok(path) => {
+                    self.candidates.push(path);
+                    received = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    received |= self.scanning;
+                    self.scanning = false;
+                    break;
+                }
+            }
+        }
+        received
+    }
+
+    /// Rank candidates against the current query and keep the best limit of them
+    fn rank(
+        &mut self,
+        config: &Config,
+        skim_matcher: &SkimMatcherV2,
+        cache: &LinkedHashMap<PathBuf, i64>,
+        limit: usize,
+    ) {
+        let mut results: Vec<MatchResult> = Vec::new();
+        for path in self.candidates.iter() {
+            let weight = config.weight(path);
+            if let Some(result) = score_path(skim_matcher, &self.query, cache, path, weight) {
+                // Without a query everything matches, only visits decide the order
+                if self.query.is_empty() || result.score > 0 {
+                    insert_ranked(&mut results, result, limit);
+                }
+            }
+        }
+        if self.query.is_empty() && results.len() < limit {
+            for path in self.candidates.iter() {
+                if results.len() >= limit {
+                    break;
+                }
+                if !results.iter().any(|r| &r.path == path) {
+                    results.push(MatchResult {
+                        path: path.clone(),
+                        score: 0,
+                        bonus: 0,
+                    });
+                }
+            }
+        }
+        self.results = results;
+        if self.selected >= self.results.len() {
+            self.selected = self.results.len().saturating_sub(1);
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => Action::Choose,
+            KeyCode::Esc => Action::Cancel,
+            KeyCode::Char('c') | KeyCode::Char('g') if ctrl => Action::Cancel,
+            KeyCode::Up => self.move_selection(-1),
+            KeyCode::Char('p') | KeyCode::Char('k') if ctrl => self.move_selection(-1),
+            KeyCode::Down | KeyCode::Tab => self.move_selection(1),
+            KeyCode::Char('n') | KeyCode::Char('j') if ctrl => self.move_selection(1),
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.selected = 0;
+                Action::Continue
+            }
+            KeyCode::Char('u') if ctrl => {
+                self.query.clear();
+                self.selected = 0;
+                Action::Continue
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.query.push(c);
+                self.selected = 0;
+                Action::Continue
+            }
+            _ => Action::Continue,
+        }
+    }
+
+    fn move_selection(&mut self, step: isize) -> Action {
+        if !self.results.is_empty() {
+            let last = self.results.len() as isize - 1;
+            self.selected = (self.selected as isize + step).clamp(0, last) as usize;
+        }
+        Action::Continue
+    }
+
+    fn chosen(&self) -> Option<PathBuf> {
+        self.results.get(self.selected).map(|r| r.path.clone())
+    }
+
+    fn draw(&self, out: &mut Stderr, width: u16) -> std::io::Result<()> {
+        queue!(out, MoveTo(0, 0), Clear(ClearType::All))?;
+        let status = if self.scanning { "scanning" } else { "done" };
+        let header = format!(
+            "> {}  ({}/{} {})",
+            self.query,
+            self.results.len(),
+            self.candidates.len(),
+            status
+        );
+        queue!(out, Print(truncate(&header, width)))?;
+
+        for (i, result) in self.results.iter().enumerate() {
+            queue!(out, MoveTo(0, i as u16 + 1))?;
+            if i == self.selected {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(
+                out,
+                Print(truncate(&result.path.to_string_lossy(), width)),
+                SetAttribute(Attribute::Reset)
+            )?;
+        }
+        queue!(
+            out,
+            MoveTo(header.chars().count().min(width as usize) as u16, 0)
+        )?;
+        out.flush()
+    }
+}
+
+fn truncate(s: &str, width: u16) -> String {
+    s.chars().take(width as usize).collect()
+}
+
+/// Restores the terminal when dropped, also when the picker panics
+struct Terminal {
+    out: Stderr,
+}
+
+impl Terminal {
+    fn enter() -> std::io::Result<Terminal> {
+        let mut out = stderr();
+        enable_raw_mode()?;
+        execute!(out, EnterAlternateScreen, Hide)?;
+        Ok(Terminal { out })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = execute!(self.out, Show, LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+/// Let the user pick a directory interactively, starting with pattern as query.
+///
+/// The list is drawn on stderr so that only the chosen path ends up on stdout.
+/// Gives None if the user cancelled or nothing matched.
+pub fn pick(config: Config, pattern: String) -> Result<Option<PathBuf>> {
+    let cache = get_visit_bonuses(config.clone())?;
+    let skim_matcher = SkimMatcherV2::default();
+
+    let (tx, rx) = channel();
+    let scan_config = config.clone();
+    let mut scanner = Some(thread::spawn(move || scan(scan_config, tx)));
+
+    let mut terminal = Terminal::enter().map_err(Error::Terminal)?;
+    let mut picker = Picker::new(pattern);
+    let mut dirty = true;
+
+    loop {
+        let (width, height) = terminal::size().map_err(Error::Terminal)?;
+        if picker.receive(&rx) || dirty {
+            let limit = height.saturating_sub(1) as usize;
+            picker.rank(&config, &skim_matcher, &cache, limit);
+            picker
+                .draw(&mut terminal.out, width)
+                .map_err(Error::Terminal)?;
+            dirty = false;
+        }
+        if !picker.scanning {
+            if let Some(Ok(Err(e))) = scanner.take().map(|s| s.join()) {
+                return Err(e);
+            }
+        }
+
+        if !event::poll(Duration::from_millis(50)).map_err(Error::Terminal)? {
+            continue;
+        }
+        match event::read().map_err(Error::Terminal)? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => match picker.handle_key(key) {
+                Action::Choose => return Ok(picker.chosen()),
+                Action::Cancel => return Ok(None),
+                Action::Continue => dirty = true,
+            },
+            Event::Resize(_, _) => dirty = true,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn picker_with(query: &str, candidates: Vec<&str>) -> Picker {
+        let mut picker = Picker::new(String::from(query));
+        picker.candidates = candidates.iter().map(PathBuf::from).collect();
+        picker.rank(
+            &test_config(),
+            &SkimMatcherV2::default(),
+            &LinkedHashMap::new(),
+            10,
+        );
+        picker
+    }
+
+    #[test]
+    fn test_rank_filters_on_query() {
+        let picker = picker_with("proj", vec!["/a/project", "/a/other", "/b/proj"]);
+        let paths: Vec<&str> = picker
+            .results
+            .iter()
+            .map(|r| r.path.to_str().unwrap())
+            .collect();
+        assert_eq!(paths.len(), 2);
+        assert!(!paths.contains(&"/a/other"));
+    }
+
+    #[test]
+    fn test_empty_query_lists_everything() {
+        let picker = picker_with("", vec!["/a", "/b"]);
+        assert_eq!(picker.results.len(), 2);
+    }
+
+    #[test]
+    fn test_edit_query_and_select() {
+        let mut picker = picker_with("", vec!["/a/one", "/a/two", "/a/three"]);
+
+        assert_eq!(picker.handle_key(key(KeyCode::Down)), Action::Continue);
+        assert_eq!(picker.chosen(), Some(PathBuf::from("/a/two")));
+        picker.handle_key(key(KeyCode::Down));
+        picker.handle_key(key(KeyCode::Down));
+        assert_eq!(picker.chosen(), Some(PathBuf::from("/a/three")));
+
+        picker.handle_key(key(KeyCode::Char('o')));
+        picker.handle_key(key(KeyCode::Char('n')));
+        picker.handle_key(key(KeyCode::Char('x')));
+        picker.handle_key(key(KeyCode::Backspace));
+        assert_eq!(picker.query, "on");
+        picker.rank(
+            &test_config(),
+            &SkimMatcherV2::default(),
+            &LinkedHashMap::new(),
+            10,
+        );
+        assert_eq!(picker.chosen(), Some(PathBuf::from("/a/one")));
+        assert_eq!(picker.handle_key(key(KeyCode::Enter)), Action::Choose);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut picker = picker_with("", vec![]);
+        assert_eq!(picker.handle_key(key(KeyCode::Esc)), Action::Cancel);
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(picker.handle_key(ctrl_c), Action::Cancel);
+        assert_eq!(picker.chosen(), None);
+    }
+
+    #[test]
+    fn test_receive_until_disconnected() {
+        let mut picker = Picker::new(String::new());
+        let (tx, rx) = channel();
+        tx.send(PathBuf::from("/a")).unwrap();
+        assert!(picker.receive(&rx));
+        assert!(picker.scanning);
+        drop(tx);
+        assert!(picker.receive(&rx));
+        assert!(!picker.scanning);
+        assert_eq!(picker.candidates, vec![PathBuf::from("/a")]);
+    }
+}
@@ -0,0 +1,147 @@
+use std::str::FromStr;
+
+/// Shells `fastjump init` can print integration for
+#[derive(Debug, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Nushell,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Shell, String> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            "nu" | "nushell" => Ok(Shell::Nushell),
+            _ => Err(format!(
+                "Unknown shell {}, expected bash, zsh, fish or nushell",
+                s
+            )),
+        }
+    }
+}
+
+const BASH: &str = r#"# fastjump integration for bash, load with: eval "$(fastjump init bash)"
+
+j() {
+    local dir
+    dir="$(command fastjump --raw -- "$*")" && [ -n "$dir" ] && builtin cd -- "$dir"
+}
+
+__fastjump_save_visit() {
+    if [ "$PWD" != "${__fastjump_last_pwd:-}" ]; then
+        __fastjump_last_pwd="$PWD"
+        command fastjump --save-visit -- "$PWD" >/dev/null 2>&1
+    fi
+}
+
+case ";${PROMPT_COMMAND:-};" in
+    *";__fastjump_save_visit;"*) ;;
+    *) PROMPT_COMMAND="__fastjump_save_visit${PROMPT_COMMAND:+;$PROMPT_COMMAND}" ;;
+esac
+
+__fastjump_complete_j() {
+    local IFS=$'\n'
+    COMPREPLY=($(command fastjump --raw --list 10 -- "${COMP_WORDS[*]:1}" 2>/dev/null | cut -f3-))
+}
+
+complete -o filenames -o nospace -F __fastjump_complete_j j
+"#;
+
+const ZSH: &str = r#"# fastjump integration for zsh, load with: eval "$(fastjump init zsh)"
+
+j() {
+    local dir
+    dir="$(command fastjump --raw -- "$*")" && [[ -n $dir ]] && builtin cd -- "$dir"
+}
+
+__fastjump_save_visit() {
+    command fastjump --save-visit -- "$PWD" >/dev/null 2>&1
+}
+
+autoload -Uz add-zsh-hook
+add-zsh-hook chpwd __fastjump_save_visit
+
+__fastjump_complete_j() {
+    local -a candidates
+    candidates=("${(@f)$(command fastjump --raw --list 10 -- "${words[2,-1]}" 2>/dev/null | cut -f3-)}")
+    compadd -U -Q -- "${(@q)candidates}"
+}
+
+if (( $+functions[compdef] )); then
+    compdef __fastjump_complete_j j
+fi
+"#;
+
+const FISH: &str = r#"# fastjump integration for fish, load with: fastjump init fish | source
+
+function j --description 'Jump to the best matching directory'
+    set -l dir (command fastjump --raw -- (string join ' ' -- $argv))
+    and test -n "$dir"
+    and builtin cd -- $dir
+end
+
+function __fastjump_save_visit --on-variable PWD
+    command fastjump --save-visit -- $PWD >/dev/null 2>&1
+end
+
+complete -c j -f -a '(command fastjump --raw --list 10 -- (commandline -ct) 2>/dev/null | string replace -r "^[^\t]*\t[^\t]*\t" "")'
+"#;
+
+const NUSHELL: &str = r#"# fastjump integration for nushell, save with: fastjump init nushell | save -f ~/.cache/fastjump.nu
+# and load it from config.nu with: source ~/.cache/fastjump.nu
+
+def "nu-complete fastjump" [context: string] {
+    let pattern = ($context | split row " " | skip 1 | str join " ")
+    ^fastjump --raw --list 10 -- $pattern | lines | each {|line| $line | split row "\t" | skip 2 | str join "\t" }
+}
+
+def --env j [...pattern: string@"nu-complete fastjump"] {
+    let dir = (^fastjump --raw -- ($pattern | str join " ") | str trim --right --char "\n")
+    if ($dir | is-not-empty) {
+        cd $dir
+    }
+}
+
+$env.config = ($env.config | upsert hooks.env_change.PWD {|config|
+    let hooks = ($config | get -i hooks.env_change.PWD | default [])
+    $hooks | append {|before, after| ^fastjump --save-visit -- $after | ignore }
+})
+"#;
+
+/// Script defining `j`, saving visited directories and completing `j` for shell
+pub fn init(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => BASH,
+        Shell::Zsh => ZSH,
+        Shell::Fish => FISH,
+        Shell::Nushell => NUSHELL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_shell() {
+        assert_eq!("bash".parse(), Ok(Shell::Bash));
+        assert_eq!("nu".parse(), Ok(Shell::Nushell));
+        assert!("tcsh".parse::<Shell>().is_err());
+    }
+
+    #[test]
+    fn test_scripts_use_raw_output_and_save_visits() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::Nushell] {
+            let script = init(shell);
+            assert!(script.contains("fastjump --raw --"));
+            assert!(script.contains("fastjump --save-visit --"));
+            assert!(script.contains("--list 10"));
+        }
+    }
+}